@@ -0,0 +1,49 @@
+//! Times `Lexer` over a generated script of about 2 MB, the size that made
+//! the old lexer quadratic.
+//!
+//! Run with `cargo bench --bench lexer`
+
+use std::time::{Duration, Instant};
+
+use lox::{error::ErrorBag, lexer::Lexer};
+
+const TARGET_BYTES: usize = 2 * 1024 * 1024;
+const ITERATIONS: usize = 10;
+
+fn generate_script() -> String {
+    let mut script = String::new();
+    let mut n = 0;
+
+    while script.len() < TARGET_BYTES {
+        script.push_str(&format!(
+            "// statement {n}\nlet value_{n} = ({n} + 2.5) * 3 >= 10;\nprint \"row {n}\";\n"
+        ));
+        n += 1;
+    }
+
+    script
+}
+
+fn main() {
+    let script = generate_script();
+    let mut total = Duration::ZERO;
+    let mut tokens = 0;
+
+    for _ in 0..ITERATIONS {
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let start = Instant::now();
+        tokens = Lexer::new(&script, &mut error_bag).count();
+        total += start.elapsed();
+
+        assert!(error_bag.errors.is_empty());
+    }
+
+    println!(
+        "lexer: {} bytes, {} tokens x {} runs, {:?} per run",
+        script.len(),
+        tokens,
+        ITERATIONS,
+        total / ITERATIONS as u32
+    );
+}
@@ -0,0 +1,64 @@
+//! Times `Interpreter::execute` on a variable-heavy script. Lexing, parsing
+//! and resolving happen outside the timed region.
+//!
+//! Run with `cargo bench --bench variable_access`
+
+use std::time::{Duration, Instant};
+
+use lox::{
+    ast::Statement, error::ErrorBag, interpreter::Interpreter, lexer::Lexer, parser::Parser,
+    resolver::Resolver,
+};
+
+const VARIABLES: usize = 64;
+const STATEMENTS: usize = 4_000;
+const ITERATIONS: usize = 200;
+
+fn generate_script() -> String {
+    let mut script = String::new();
+
+    for i in 0..VARIABLES {
+        script.push_str(&format!("let v{i} = {i};\n"));
+    }
+
+    for n in 0..STATEMENTS {
+        let (i, j) = (n % VARIABLES, (n * 7) % VARIABLES);
+        script.push_str(&format!("v{i} = (v{i} + v{j} * 2 - v{j}) % 1000;\n"));
+    }
+
+    script
+}
+
+// Same type `Parser::parse` returns
+#[allow(clippy::vec_box)]
+fn compile(script: &str) -> Vec<Box<Statement>> {
+    let mut error_bag = ErrorBag { errors: vec![] };
+    let tokens: Vec<_> = Lexer::new(script, &mut error_bag).collect();
+    let mut ast = Parser::new(tokens).parse().unwrap();
+    Resolver::new(&mut error_bag).resolve(&mut ast);
+    assert!(error_bag.errors.is_empty());
+    ast
+}
+
+fn main() {
+    let script = generate_script();
+    let mut total = Duration::ZERO;
+
+    for _ in 0..ITERATIONS {
+        let ast = compile(&script);
+        let mut interpreter = Interpreter::new();
+
+        let start = Instant::now();
+        if interpreter.execute(ast).is_err() {
+            panic!("benchmark script failed to run");
+        }
+        total += start.elapsed();
+    }
+
+    println!(
+        "variable_access: {} statements x {} runs, {:?} per run",
+        STATEMENTS + VARIABLES,
+        ITERATIONS,
+        total / ITERATIONS as u32
+    );
+}
@@ -0,0 +1,50 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lox::{
+    cancel::CancellationToken,
+    compiler::Compiler,
+    error::ErrorBag,
+    interpreter::Interpreter,
+    lexer::{Lexer, TokenKind},
+    optimizer::{fold_constants, peephole},
+    parser::Parser,
+    resolver::Resolver,
+    vm::VM,
+};
+
+/// Cancellation checks allowed per run, so no input can run for long
+const STEP_LIMIT: usize = 10_000;
+
+// Runs every program that gets through the front end on both the
+// tree-walker and the optimized bytecode VM. Runtime failures must be
+// `LoxError`s, never a panic.
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mut error_bag = ErrorBag { errors: vec![] };
+    let tokens: Vec<_> = Lexer::new(source, &mut error_bag)
+        .filter(|token| !matches!(token.kind, TokenKind::Comment | TokenKind::Invalid))
+        .collect();
+    let Ok(mut ast) = Parser::new(tokens).parse() else {
+        return;
+    };
+    Resolver::new(&mut error_bag).resolve(&mut ast);
+    if !error_bag.errors.is_empty() {
+        return;
+    }
+
+    let mut optimized = ast.clone();
+    fold_constants(&mut optimized);
+    let chunk = Compiler::new(&mut error_bag).compile(&optimized);
+
+    let _ =
+        Interpreter::with_cancellation(CancellationToken::with_step_limit(STEP_LIMIT)).execute(ast);
+
+    if error_bag.errors.is_empty() {
+        VM::with_cancellation(CancellationToken::with_step_limit(STEP_LIMIT))
+            .interpret(peephole(chunk));
+    }
+});
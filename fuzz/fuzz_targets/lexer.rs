@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lox::{error::ErrorBag, lexer::Lexer};
+
+// Any text must lex to tokens and errors, never a panic
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mut error_bag = ErrorBag { errors: vec![] };
+    for _ in Lexer::new(source, &mut error_bag) {}
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lox::{
+    error::ErrorBag,
+    lexer::{Lexer, TokenKind},
+    parser::Parser,
+};
+
+// Malformed programs must come back as a `ParseError`
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mut error_bag = ErrorBag { errors: vec![] };
+    let tokens: Vec<_> = Lexer::new(source, &mut error_bag)
+        .filter(|token| !matches!(token.kind, TokenKind::Comment | TokenKind::Invalid))
+        .collect();
+
+    let _ = Parser::new(tokens).parse();
+});
@@ -1,4 +1,7 @@
-use crate::lexer::Token;
+use crate::{
+    intern::Symbol,
+    lexer::{Position, Token},
+};
 
 #[derive(Debug, Clone)]
 pub enum LiteralKind {
@@ -6,14 +9,15 @@ pub enum LiteralKind {
     Decimal(f64),
     QuotedString(String),
     Boolean(bool),
-    Identifier(String),
     None,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Statement {
-    Print(Box<Expression>),
-    Let(String, Box<Expression>),
+    /// Printed value and the position of the `print` keyword
+    Print(Box<Expression>, Position),
+    /// Declared variable, initializer and the `///` doc comment above it
+    Let(Variable, Box<Expression>, Option<String>),
     Expr(Box<Expression>),
 }
 
@@ -22,8 +26,27 @@ pub enum Expression {
     Binary(BinaryExpr),
     Grouping(Box<Expression>),
     Unary(UnaryExpr),
-    Literal(LiteralKind),
-    Assign(String, Box<Expression>),
+    Literal(LiteralKind, Position),
+    Variable(Variable),
+    Assign(Variable, Box<Expression>),
+    /// String literal parts and embedded expressions in source order
+    Interpolation(Vec<Box<Expression>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: Symbol,
+    pub span: Position,
+    /// Filled in by the resolver before the program runs
+    pub slot: Option<Slot>,
+}
+
+/// Where a variable lives: how many scopes to walk out from the current one
+/// and its index inside that scope
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -41,13 +64,12 @@ pub struct UnaryExpr {
 
 impl std::fmt::Display for LiteralKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            &Self::Integer(i) => write!(f, "{}", i),
-            &Self::Decimal(d) => write!(f, "{}", d),
-            &Self::Boolean(b) => write!(f, "{}", b),
-            &Self::QuotedString(ref s) => write!(f, "{}", s),
-            &Self::Identifier(ref s) => write!(f, "{}", s),
-            &Self::None => write!(f, "None"),
+        match *self {
+            Self::Integer(i) => write!(f, "{}", i),
+            Self::Decimal(d) => write!(f, "{}", d),
+            Self::Boolean(b) => write!(f, "{}", b),
+            Self::QuotedString(ref s) => write!(f, "{}", s),
+            Self::None => write!(f, "None"),
         }
     }
 }
@@ -1,24 +1,271 @@
-#[derive(Clone)]
-#[allow(unused)]
+use crate::{lexer::Position, value::Value};
+
+/// Every instruction is one byte, followed by its operands. Constant,
+/// global and jump operands are two bytes (big endian); local slots,
+/// upvalue indices, call argument counts and interpolation part counts one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum OpCode {
     OpConstant,
+    OpNone,
+    OpTrue,
+    OpFalse,
+    OpPop,
+    OpDefineGlobal,
+    OpGetGlobal,
+    OpSetGlobal,
+    OpGetLocal,
+    OpSetLocal,
+    OpEqual,
+    OpNotEqual,
+    OpGreater,
+    OpGreaterEqual,
+    OpLess,
+    OpLessEqual,
+    OpAdd,
+    OpSubtract,
+    OpMultiply,
+    OpDivide,
+    OpModulo,
+    OpNot,
+    OpNegate,
+    OpPrint,
+    OpJump,
+    OpJumpIfFalse,
+    OpLoop,
     OpReturn,
+    OpCall,
+    OpClosure,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpCloseUpvalue,
+    OpInterpolate,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 34] = [
+        OpCode::OpConstant,
+        OpCode::OpNone,
+        OpCode::OpTrue,
+        OpCode::OpFalse,
+        OpCode::OpPop,
+        OpCode::OpDefineGlobal,
+        OpCode::OpGetGlobal,
+        OpCode::OpSetGlobal,
+        OpCode::OpGetLocal,
+        OpCode::OpSetLocal,
+        OpCode::OpEqual,
+        OpCode::OpNotEqual,
+        OpCode::OpGreater,
+        OpCode::OpGreaterEqual,
+        OpCode::OpLess,
+        OpCode::OpLessEqual,
+        OpCode::OpAdd,
+        OpCode::OpSubtract,
+        OpCode::OpMultiply,
+        OpCode::OpDivide,
+        OpCode::OpModulo,
+        OpCode::OpNot,
+        OpCode::OpNegate,
+        OpCode::OpPrint,
+        OpCode::OpJump,
+        OpCode::OpJumpIfFalse,
+        OpCode::OpLoop,
+        OpCode::OpReturn,
+        OpCode::OpCall,
+        OpCode::OpClosure,
+        OpCode::OpGetUpvalue,
+        OpCode::OpSetUpvalue,
+        OpCode::OpCloseUpvalue,
+        OpCode::OpInterpolate,
+    ];
+
+    /// Number of operand bytes following the opcode
+    pub fn operand_width(&self) -> usize {
+        match self {
+            Self::OpConstant
+            | Self::OpDefineGlobal
+            | Self::OpGetGlobal
+            | Self::OpSetGlobal
+            | Self::OpJump
+            | Self::OpJumpIfFalse
+            | Self::OpLoop
+            | Self::OpClosure => 2,
+            Self::OpGetLocal
+            | Self::OpSetLocal
+            | Self::OpCall
+            | Self::OpGetUpvalue
+            | Self::OpSetUpvalue
+            | Self::OpInterpolate => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Self::ALL.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+/// Source position shared by `length` consecutive bytes of code
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineRun {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
 }
 
-#[derive(Clone)]
-#[allow(unused)]
+#[derive(Debug, Clone, Default)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
-    /// For now we'll only allow to store f64
-    pub constant_pool: Vec<f64>,
+    pub code: Vec<u8>,
+    pub constant_pool: Vec<Value>,
+    /// Run-length encoded positions, parallel to `code`
+    pub lines: Vec<LineRun>,
 }
 
 impl Chunk {
-    #[allow(unused)]
     pub fn new() -> Self {
-        Self {
-            code: Vec::new(),
-            constant_pool: Vec::new(),
+        Self::default()
+    }
+
+    pub fn write(&mut self, byte: u8, span: &Position) {
+        self.code.push(byte);
+
+        match self.lines.last_mut() {
+            Some(run) if run.line == span.line && run.column == span.column => run.length += 1,
+            _ => self.lines.push(LineRun {
+                line: span.line,
+                column: span.column,
+                length: 1,
+            }),
+        }
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: &Position) {
+        self.write(op as u8, span);
+    }
+
+    pub fn write_u16(&mut self, operand: u16, span: &Position) {
+        for byte in operand.to_be_bytes() {
+            self.write(byte, span);
+        }
+    }
+
+    /// Source position of the byte at `offset`
+    pub fn position(&self, offset: usize) -> Option<Position> {
+        let mut start = 0;
+        for run in &self.lines {
+            if offset < start + run.length {
+                return Some(Position::new(run.line, run.column));
+            }
+            start += run.length;
         }
+
+        None
+    }
+
+    /// Source position of every byte of code, in order
+    pub fn positions(&self) -> impl Iterator<Item = Position> + '_ {
+        self.lines
+            .iter()
+            .flat_map(|run| std::iter::repeat_n(Position::new(run.line, run.column), run.length))
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Emits a jump with a placeholder offset and returns where to patch it
+    pub fn write_jump(&mut self, op: OpCode, span: &Position) -> usize {
+        self.write_op(op, span);
+        self.write_u16(u16::MAX, span);
+        self.code.len() - 2
+    }
+
+    /// Points the jump at `offset` to the next instruction to be written
+    pub fn patch_jump(&mut self, offset: usize) -> Option<()> {
+        let jump = u16::try_from(self.code.len() - offset - 2).ok()?;
+        self.code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+        Some(())
+    }
+
+    /// Returns `None` once the pool outgrows a two byte operand
+    pub fn add_constant(&mut self, value: Value) -> Option<u16> {
+        let index = u16::try_from(self.constant_pool.len()).ok()?;
+        self.constant_pool.push(value);
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Chunk, LineRun, OpCode};
+    use crate::{
+        lexer::Position,
+        verifier::verify,
+        vm::{InterpretResult, VM},
+    };
+
+    #[test]
+    fn chunk_run_length_encodes_positions() {
+        let mut chunk = Chunk::new();
+        let first = Position::new(1, 5);
+        let second = Position::new(2, 1);
+
+        chunk.write_op(OpCode::OpConstant, &first);
+        chunk.write_u16(0, &first);
+        chunk.write_op(OpCode::OpPrint, &second);
+
+        assert_eq!(
+            chunk.lines,
+            vec![
+                LineRun {
+                    line: 1,
+                    column: 5,
+                    length: 3
+                },
+                LineRun {
+                    line: 2,
+                    column: 1,
+                    length: 1
+                },
+            ]
+        );
+        assert_eq!(chunk.position(2).map(|p| p.line), Some(1));
+        assert_eq!(chunk.position(3).map(|p| p.line), Some(2));
+        assert!(chunk.position(4).is_none());
+    }
+
+    #[test]
+    fn chunk_patches_jumps_to_the_next_instruction() {
+        let span = Position::new(1, 1);
+        let mut chunk = Chunk::new();
+
+        chunk.write_op(OpCode::OpFalse, &span);
+        let jump = chunk.write_jump(OpCode::OpJumpIfFalse, &span);
+        chunk.write_op(OpCode::OpTrue, &span);
+        chunk.write_op(OpCode::OpPrint, &span);
+        assert_eq!(chunk.patch_jump(jump), Some(()));
+        chunk.write_op(OpCode::OpPop, &span);
+        chunk.write_op(OpCode::OpNone, &span);
+        chunk.write_op(OpCode::OpReturn, &span);
+
+        assert_eq!(jump, 2);
+        assert_eq!(chunk.read_u16(jump), 2);
+        assert!(verify(&chunk).is_ok());
+        assert_eq!(
+            VM::new().interpret(chunk.clone()),
+            InterpretResult::InterpretOk
+        );
+
+        let far = chunk.write_jump(OpCode::OpJump, &span);
+        chunk
+            .code
+            .resize(far + 2 + u16::MAX as usize + 1, OpCode::OpNone as u8);
+        assert_eq!(chunk.patch_jump(far), None);
     }
 }
@@ -0,0 +1,125 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::error::LoxError;
+
+/// Handle used to stop a running script, either from another thread, once
+/// a wall-clock deadline has passed or after a number of steps. Clones
+/// share the same flag and step count.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<(Instant, Duration)>,
+    /// Steps taken so far and how many are allowed
+    steps: Option<(Arc<AtomicUsize>, usize)>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            deadline: Some((Instant::now() + timeout, timeout)),
+            steps: None,
+        }
+    }
+
+    /// Stops the script after `limit` checks, which unlike a timeout gives
+    /// the same result on every run. The interpreter checks before every
+    /// statement and the `VM` every `CANCEL_CHECK_INTERVAL` instructions.
+    pub fn with_step_limit(limit: usize) -> Self {
+        Self {
+            steps: Some((Arc::new(AtomicUsize::new(0)), limit)),
+            ..Self::default()
+        }
+    }
+
+    #[allow(unused)]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Called by the interpreter and the VM between units of work
+    pub fn check(&self) -> Result<(), LoxError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(LoxError::Interrupted("Script was cancelled".to_string()));
+        }
+
+        if let Some((ref taken, limit)) = self.steps {
+            if taken.fetch_add(1, Ordering::Relaxed) >= limit {
+                return Err(LoxError::Interrupted(format!(
+                    "Script ran past its limit of \x1b[32m{limit}\x1b[0m steps"
+                )));
+            }
+        }
+
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => {
+                Err(LoxError::Interrupted(format!(
+                    "Script timed out after \x1b[32m{}ms\x1b[0m",
+                    timeout.as_millis()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::CancellationToken;
+    use crate::{error::LoxError, interpreter::Interpreter, test_support::resolve};
+    use std::time::Duration;
+
+    #[allow(clippy::vec_box)]
+    fn program() -> Vec<Box<crate::ast::Statement>> {
+        resolve("let x = 1; x = x + 1;")
+    }
+
+    #[test]
+    fn cancelled_token_interrupts_execution() {
+        let token = CancellationToken::new();
+        let mut interpreter = Interpreter::with_cancellation(token.clone());
+
+        std::thread::spawn(move || token.cancel()).join().unwrap();
+
+        assert!(matches!(
+            interpreter.execute(program()),
+            Err(LoxError::Interrupted(_))
+        ));
+    }
+
+    #[test]
+    fn expired_timeout_interrupts_execution() {
+        let mut interpreter =
+            Interpreter::with_cancellation(CancellationToken::with_timeout(Duration::ZERO));
+
+        assert!(matches!(
+            interpreter.execute(program()),
+            Err(LoxError::Interrupted(_))
+        ));
+        assert!(Interpreter::new().execute(program()).is_ok());
+    }
+
+    #[test]
+    fn step_limit_interrupts_execution() {
+        let mut interpreter = Interpreter::with_cancellation(CancellationToken::with_step_limit(1));
+
+        assert!(matches!(
+            interpreter.execute(program()),
+            Err(LoxError::Interrupted(_))
+        ));
+
+        let mut interpreter = Interpreter::with_cancellation(CancellationToken::with_step_limit(2));
+        assert!(interpreter.execute(program()).is_ok());
+    }
+}
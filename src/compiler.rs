@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{BinaryExpr, Expression, LiteralKind, Statement, UnaryExpr, Variable},
+    bytecode::{Chunk, OpCode},
+    error::{ErrorBag, LoxError},
+    intern::Symbol,
+    lexer::{Position, TokenKind},
+    value::Value,
+};
+
+/// Key of a deduplicated constant. Decimals compare by their bits, so
+/// `0.0` and `-0.0` stay apart and equal NaNs share a slot.
+#[derive(PartialEq, Eq, Hash)]
+enum Constant {
+    Integer(isize),
+    Decimal(u64),
+    String(Symbol),
+}
+
+/// Walks a resolved program and emits bytecode for the `VM`
+pub struct Compiler<'a> {
+    chunk: Chunk,
+    scope_depth: usize,
+    /// Position of the node being compiled, recorded for every emitted byte
+    span: Position,
+    /// Pool index of every constant, so repeated literals share one
+    constants: HashMap<Constant, u16>,
+    /// Set once the pool overflowed, so the error is reported only once
+    pool_full: bool,
+    pub error_bag: &'a mut ErrorBag,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(error_bag: &'a mut ErrorBag) -> Self {
+        Self {
+            chunk: Chunk::new(),
+            scope_depth: 0,
+            span: Position::new(1, 1),
+            constants: HashMap::new(),
+            pool_full: false,
+            error_bag,
+        }
+    }
+
+    pub fn compile(mut self, statements: &[Box<Statement>]) -> Chunk {
+        for statement in statements {
+            self.statement(statement);
+        }
+
+        // The implicit return keeps the position of the last statement
+        self.emit(OpCode::OpNone);
+        self.emit(OpCode::OpReturn);
+        self.chunk
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        self.chunk.write_op(op, &self.span);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        self.chunk.write(byte, &self.span);
+    }
+
+    fn emit_u16(&mut self, operand: u16) {
+        self.chunk.write_u16(operand, &self.span);
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Print(expr, keyword) => {
+                self.expression(expr);
+                self.span = keyword.clone();
+                self.emit(OpCode::OpPrint);
+            }
+            Statement::Expr(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::OpPop);
+            }
+            Statement::Let(variable, initializer, _) => {
+                self.span = variable.span.clone();
+                self.expression(initializer);
+                // Locals stay in the stack slot their initializer was pushed to
+                if self.scope_depth == 0 {
+                    self.variable_op(OpCode::OpDefineGlobal, OpCode::OpDefineGlobal, variable);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Binary(binary) => self.binary(binary),
+            Expression::Unary(unary) => self.unary(unary),
+            Expression::Grouping(inner) => self.expression(inner),
+            Expression::Literal(literal, span) => {
+                self.span = span.clone();
+                self.literal(literal);
+            }
+            Expression::Variable(variable) => {
+                self.variable_op(OpCode::OpGetGlobal, OpCode::OpGetLocal, variable)
+            }
+            Expression::Assign(variable, value) => {
+                self.expression(value);
+                self.variable_op(OpCode::OpSetGlobal, OpCode::OpSetLocal, variable);
+            }
+            Expression::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part);
+                }
+                match u8::try_from(parts.len()) {
+                    Ok(count) => {
+                        self.emit(OpCode::OpInterpolate);
+                        self.emit_byte(count);
+                    }
+                    Err(_) => self.error_bag.errors.push(LoxError::CompileError(format!(
+                        "String interpolation has more than {} parts at line {} column {}",
+                        u8::MAX,
+                        self.span.line,
+                        self.span.column
+                    ))),
+                }
+            }
+        }
+    }
+
+    fn literal(&mut self, literal: &LiteralKind) {
+        match literal {
+            LiteralKind::None => self.emit(OpCode::OpNone),
+            LiteralKind::Boolean(true) => self.emit(OpCode::OpTrue),
+            LiteralKind::Boolean(false) => self.emit(OpCode::OpFalse),
+            _ => match self.constant(literal) {
+                Some(index) => {
+                    self.emit(OpCode::OpConstant);
+                    self.emit_u16(index);
+                }
+                None if !self.pool_full => {
+                    self.pool_full = true;
+                    self.error_bag.errors.push(LoxError::CompileError(format!(
+                        "Too many constants in one chunk at line {} column {}",
+                        self.span.line, self.span.column
+                    )));
+                }
+                None => {}
+            },
+        }
+    }
+
+    fn constant(&mut self, literal: &LiteralKind) -> Option<u16> {
+        let key = match *literal {
+            LiteralKind::Integer(i) => Constant::Integer(i),
+            LiteralKind::Decimal(d) => Constant::Decimal(d.to_bits()),
+            LiteralKind::QuotedString(ref s) => Constant::String(Symbol::intern(s)),
+            LiteralKind::Boolean(_) | LiteralKind::None => {
+                return self.chunk.add_constant(Value::from(literal))
+            }
+        };
+        if let Some(&index) = self.constants.get(&key) {
+            return Some(index);
+        }
+
+        let value = match key {
+            Constant::String(symbol) => Value::String(symbol.as_str().into()),
+            _ => Value::from(literal),
+        };
+        let index = self.chunk.add_constant(value)?;
+        self.constants.insert(key, index);
+        Some(index)
+    }
+
+    fn binary(&mut self, binary: &BinaryExpr) {
+        self.expression(&binary.lhs);
+        self.expression(&binary.rhs);
+        self.span = binary.operator.span.clone();
+
+        let op = match binary.operator.kind {
+            TokenKind::Plus => OpCode::OpAdd,
+            TokenKind::Minus => OpCode::OpSubtract,
+            TokenKind::Asterisk => OpCode::OpMultiply,
+            TokenKind::ForwardSlash => OpCode::OpDivide,
+            TokenKind::Percentage => OpCode::OpModulo,
+            TokenKind::Equal => OpCode::OpEqual,
+            TokenKind::NotEqual => OpCode::OpNotEqual,
+            TokenKind::GreaterThan => OpCode::OpGreater,
+            TokenKind::GreaterEqual => OpCode::OpGreaterEqual,
+            TokenKind::LessThan => OpCode::OpLess,
+            TokenKind::LessEqual => OpCode::OpLessEqual,
+            ref other => {
+                self.error_bag.errors.push(LoxError::CompileError(format!(
+                    "Binary expression should not contain operator {}",
+                    other
+                )));
+                return;
+            }
+        };
+
+        self.emit(op);
+    }
+
+    fn unary(&mut self, unary: &UnaryExpr) {
+        self.expression(&unary.rhs);
+        self.span = unary.operator.span.clone();
+
+        match unary.operator.kind {
+            TokenKind::Minus => self.emit(OpCode::OpNegate),
+            TokenKind::Bang => self.emit(OpCode::OpNot),
+            ref other => self.error_bag.errors.push(LoxError::CompileError(format!(
+                "Unary expression should not contain operator {}",
+                other
+            ))),
+        }
+    }
+
+    /// Globals are addressed by their resolver slot, locals by their stack slot
+    fn variable_op(&mut self, global: OpCode, local: OpCode, variable: &Variable) {
+        let Some(slot) = variable.slot else {
+            self.error_bag.errors.push(LoxError::CompileError(format!(
+                "Use of unresolved identifier \x1b[32m{}\x1b[0m at line {} column {}",
+                variable.name, variable.span.line, variable.span.column
+            )));
+            return;
+        };
+        self.span = variable.span.clone();
+
+        if self.scope_depth == 0 || slot.depth == self.scope_depth {
+            match u16::try_from(slot.index) {
+                Ok(index) => {
+                    self.emit(global);
+                    self.emit_u16(index);
+                }
+                Err(_) => self.error_bag.errors.push(LoxError::CompileError(
+                    "Too many global variables".to_string(),
+                )),
+            }
+        } else {
+            match u8::try_from(slot.index) {
+                Ok(index) => {
+                    self.emit(local);
+                    self.emit_byte(index);
+                }
+                Err(_) => self.error_bag.errors.push(LoxError::CompileError(
+                    "Too many local variables in scope".to_string(),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Compiler;
+    use crate::{
+        bytecode::OpCode,
+        error::{ErrorBag, LoxError},
+        lexer::Position,
+        test_support::{compile, resolve},
+    };
+
+    #[test]
+    fn compiler_emits_bytecode() {
+        let chunk = compile("let x = 2; print -x * 3;");
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                0,
+                OpCode::OpDefineGlobal as u8,
+                0,
+                0,
+                OpCode::OpGetGlobal as u8,
+                0,
+                0,
+                OpCode::OpNegate as u8,
+                OpCode::OpConstant as u8,
+                0,
+                1,
+                OpCode::OpMultiply as u8,
+                OpCode::OpPrint as u8,
+                OpCode::OpNone as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+        assert_eq!(chunk.constant_pool.len(), 2);
+    }
+
+    #[test]
+    fn compiler_shares_constants() {
+        let chunk = compile(
+            "let a = \"hi\"; let b = \"hi\"; print \"bye\"; \
+             print 1; print 1; print 1.0; print 2.5; print 2.5; print -0.0;",
+        );
+
+        // "hi", "bye", 1, 1.0, 2.5 and the 0.0 that -0.0 negates
+        assert_eq!(chunk.constant_pool.len(), 6);
+    }
+
+    #[test]
+    fn compiler_reports_a_full_constant_pool_once() {
+        let program: String = (0..=u16::MAX as usize + 1)
+            .map(|i| format!("print {i};\n"))
+            .collect();
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        Compiler::new(&mut error_bag).compile(&resolve(&program));
+
+        assert_eq!(error_bag.errors.len(), 1);
+        assert!(matches!(
+            error_bag.errors[0],
+            LoxError::CompileError(ref message) if message.ends_with("at line 65537 column 7")
+        ));
+    }
+
+    #[test]
+    fn compiler_records_the_position_of_every_instruction() {
+        let chunk = compile("let x = 1;\nprint x\n  + 2;\nprint \"a\";\nprint\n  true;");
+
+        let expected = [
+            (0, Position::new(1, 9)),  // OpConstant 1
+            (3, Position::new(1, 5)),  // OpDefineGlobal x
+            (6, Position::new(2, 7)),  // OpGetGlobal x
+            (9, Position::new(3, 5)),  // OpConstant 2
+            (12, Position::new(3, 3)), // OpAdd
+            (13, Position::new(2, 1)), // OpPrint
+            (14, Position::new(4, 7)), // OpConstant "a"
+            (17, Position::new(4, 1)), // OpPrint
+            (18, Position::new(6, 3)), // OpTrue
+            (19, Position::new(5, 1)), // OpPrint
+            (20, Position::new(5, 1)), // OpNone
+            (21, Position::new(5, 1)), // OpReturn
+        ];
+        assert_eq!(chunk.code.len(), 22);
+        for (offset, position) in expected {
+            let found = chunk.position(offset).unwrap();
+            assert_eq!(
+                (found.line, found.column),
+                (position.line, position.column),
+                "offset {offset}"
+            );
+        }
+    }
+
+    #[test]
+    fn compiler_addresses_variables_in_inner_scopes_by_stack_slot() {
+        let ast = resolve("let x = 1; x = 2; print x;");
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        // The grammar has no blocks yet, so compile as if inside one. The
+        // slots resolve one scope out, which is the innermost scope here.
+        let mut compiler = Compiler::new(&mut error_bag);
+        compiler.scope_depth = 1;
+        let chunk = compiler.compile(&ast);
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                0,
+                OpCode::OpConstant as u8,
+                0,
+                1,
+                OpCode::OpSetLocal as u8,
+                0,
+                OpCode::OpPop as u8,
+                OpCode::OpGetLocal as u8,
+                0,
+                OpCode::OpPrint as u8,
+                OpCode::OpNone as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use std::mem::discriminant;
+
+use crate::{
+    error::ErrorBag,
+    lexer::{Lexer, Position, TokenKind},
+    parser::MAX_NESTING,
+};
+
+/// Lossless syntax tree for formatters and refactoring tools. Every byte of
+/// the source belongs to exactly one token or one piece of trivia, so
+/// printing the tree gives back the original text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyntaxKind {
+    Root,
+    LetStatement,
+    PrintStatement,
+    ExprStatement,
+    Assign,
+    Binary,
+    Unary,
+    Grouping,
+    Literal,
+    Variable,
+    Interpolation,
+    /// Tokens that do not fit the grammar, kept so nothing is lost
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+    DocComment,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+}
+
+/// A token with the whitespace and comments in front of it
+#[derive(Debug, Clone)]
+pub struct SyntaxToken {
+    pub leading: Vec<Trivia>,
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Position,
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxNode {
+    pub kind: SyntaxKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl std::fmt::Display for SyntaxNode {
+    /// Walks the tree with an explicit stack, since long operator chains
+    /// nest deeper than the call stack allows
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut stack = vec![self.children.iter()];
+        while let Some(children) = stack.last_mut() {
+            match children.next() {
+                Some(SyntaxElement::Node(node)) => stack.push(node.children.iter()),
+                Some(SyntaxElement::Token(token)) => {
+                    for trivia in &token.leading {
+                        write!(f, "{}", trivia.text)?;
+                    }
+                    write!(f, "{}", token.text)?;
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds the tree for `source`. Unlike `Parser` it never gives up:
+/// anything unexpected becomes an `Error` node. The last child of the
+/// root is an `Eof` token holding the trivia at the end of the file.
+pub fn parse(source: &str, error_bag: &mut ErrorBag) -> SyntaxNode {
+    let tokens = tokens_with_trivia(source, error_bag);
+    let mut parser = CstParser::new(tokens, 0);
+
+    let mut children = Vec::new();
+    while !parser.at(&TokenKind::Eof) {
+        children.push(parser.statement());
+    }
+    children.push(parser.bump());
+
+    SyntaxNode {
+        kind: SyntaxKind::Root,
+        children,
+    }
+}
+
+/// Lexes `source` and turns comments and the gaps between tokens into
+/// trivia on the token that follows them
+pub fn tokens_with_trivia(source: &str, error_bag: &mut ErrorBag) -> Vec<SyntaxToken> {
+    TriviaLexer::new(source, Lexer::new(source, error_bag)).collect()
+}
+
+/// Wraps a `Lexer` to hand out tokens with their leading trivia. The
+/// `Eof` token holds the trivia at the end of the file.
+pub struct TriviaLexer<'a> {
+    source: &'a str,
+    lexer: Lexer<'a>,
+    /// Byte offset right after the last token or trivia handed out
+    end: usize,
+}
+
+impl<'a> TriviaLexer<'a> {
+    pub fn new(source: &'a str, lexer: Lexer<'a>) -> Self {
+        Self {
+            source,
+            end: lexer.position().offset,
+            lexer,
+        }
+    }
+
+    /// Position where the trivia of the next token starts
+    pub fn position(&self) -> &Position {
+        self.lexer.position()
+    }
+
+    pub fn in_interpolation(&self) -> bool {
+        self.lexer.in_interpolation()
+    }
+
+    fn whitespace_until(&mut self, offset: usize, leading: &mut Vec<Trivia>) {
+        if offset > self.end {
+            leading.push(Trivia {
+                kind: TriviaKind::Whitespace,
+                text: self.source[self.end..offset].to_string(),
+            });
+        }
+    }
+}
+
+impl Iterator for TriviaLexer<'_> {
+    type Item = SyntaxToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut leading = Vec::new();
+        while let Some(token) = self.lexer.next_token() {
+            let start = token.span.offset;
+            self.whitespace_until(start, &mut leading);
+            self.end = start + token.length;
+            let text = self.source[start..self.end].to_string();
+
+            match token.kind {
+                TokenKind::Comment => leading.push(Trivia {
+                    kind: TriviaKind::Comment,
+                    text,
+                }),
+                TokenKind::DocComment(_) => leading.push(Trivia {
+                    kind: TriviaKind::DocComment,
+                    text,
+                }),
+                kind => {
+                    return Some(SyntaxToken {
+                        leading,
+                        kind,
+                        text,
+                        span: token.span,
+                    })
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Binary operators from the loosest binding to the tightest, the same
+/// precedence `Parser` uses
+const BINARY_LEVELS: [&[TokenKind]; 4] = [
+    &[TokenKind::Equal, TokenKind::NotEqual],
+    &[
+        TokenKind::GreaterThan,
+        TokenKind::GreaterEqual,
+        TokenKind::LessThan,
+        TokenKind::LessEqual,
+    ],
+    &[TokenKind::Minus, TokenKind::Plus],
+    &[
+        TokenKind::ForwardSlash,
+        TokenKind::Asterisk,
+        TokenKind::Percentage,
+    ],
+];
+
+pub(crate) struct CstParser {
+    pub(crate) tokens: Vec<SyntaxToken>,
+    pub(crate) cursor: usize,
+    /// Expression nesting at the token being parsed, limited like `Parser`
+    depth: usize,
+}
+
+impl CstParser {
+    pub(crate) fn new(tokens: Vec<SyntaxToken>, cursor: usize) -> Self {
+        Self {
+            tokens,
+            cursor,
+            depth: 0,
+        }
+    }
+
+    pub(crate) fn at(&self, kind: &TokenKind) -> bool {
+        discriminant(&self.tokens[self.cursor].kind) == discriminant(kind)
+    }
+
+    fn at_identifier(&self) -> bool {
+        matches!(self.tokens[self.cursor].kind, TokenKind::Identifier(_))
+    }
+
+    fn at_any(&self, kinds: &[TokenKind]) -> bool {
+        kinds.iter().any(|kind| self.at(kind))
+    }
+
+    /// Takes the current token. The `Eof` token is never passed.
+    pub(crate) fn bump(&mut self) -> SyntaxElement {
+        let token = self.tokens[self.cursor].clone();
+        if self.cursor < self.tokens.len() - 1 {
+            self.cursor += 1;
+        }
+        SyntaxElement::Token(token)
+    }
+
+    fn bump_if(&mut self, kind: &TokenKind, children: &mut Vec<SyntaxElement>) {
+        if self.at(kind) {
+            children.push(self.bump());
+        }
+    }
+
+    pub(crate) fn statement(&mut self) -> SyntaxElement {
+        let mut children = Vec::new();
+
+        let kind = if self.at(&TokenKind::Let) {
+            children.push(self.bump());
+            if self.at_identifier() {
+                children.push(self.bump());
+            }
+            if self.at(&TokenKind::Assign) {
+                children.push(self.bump());
+                children.push(self.expression());
+            }
+            SyntaxKind::LetStatement
+        } else if self.at(&TokenKind::Print) {
+            children.push(self.bump());
+            children.push(self.expression());
+            SyntaxKind::PrintStatement
+        } else {
+            children.push(self.expression());
+            SyntaxKind::ExprStatement
+        };
+        self.bump_if(&TokenKind::Semicolon, &mut children);
+
+        node(kind, children)
+    }
+
+    /// Past `MAX_NESTING` the current token becomes an `Error` node. The
+    /// parser then unwinds, and what follows starts a new statement.
+    fn too_deep(&mut self) -> SyntaxElement {
+        match self.at(&TokenKind::Eof) {
+            true => node(SyntaxKind::Error, Vec::new()),
+            false => {
+                let token = self.bump();
+                node(SyntaxKind::Error, vec![token])
+            }
+        }
+    }
+
+    fn expression(&mut self) -> SyntaxElement {
+        if self.depth == MAX_NESTING {
+            return self.too_deep();
+        }
+        self.depth += 1;
+
+        let mut target = self.binary(0);
+        if self.at(&TokenKind::Assign) {
+            let operator = self.bump();
+            let value = self.expression();
+            target = node(SyntaxKind::Assign, vec![target, operator, value]);
+        }
+
+        self.depth -= 1;
+        target
+    }
+
+    /// Precedence climbing: operators binding at least as tight as `level`
+    /// extend the chain, tighter ones are parsed into the right operand
+    fn binary(&mut self, level: usize) -> SyntaxElement {
+        let mut lhs = self.unary();
+        while let Some(found) =
+            (level..BINARY_LEVELS.len()).find(|&l| self.at_any(BINARY_LEVELS[l]))
+        {
+            let operator = self.bump();
+            let rhs = self.binary(found + 1);
+            lhs = node(SyntaxKind::Binary, vec![lhs, operator, rhs]);
+        }
+        lhs
+    }
+
+    fn unary(&mut self) -> SyntaxElement {
+        if self.at_any(&[TokenKind::Bang, TokenKind::Minus]) {
+            if self.depth == MAX_NESTING {
+                return self.too_deep();
+            }
+            self.depth += 1;
+            let operator = self.bump();
+            let operand = self.unary();
+            self.depth -= 1;
+            return node(SyntaxKind::Unary, vec![operator, operand]);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> SyntaxElement {
+        let current = &self.tokens[self.cursor].kind;
+        match current {
+            TokenKind::True
+            | TokenKind::False
+            | TokenKind::None
+            | TokenKind::Integer(_)
+            | TokenKind::Decimal(_)
+            | TokenKind::QuotedString(_) => {
+                let token = self.bump();
+                node(SyntaxKind::Literal, vec![token])
+            }
+            TokenKind::Identifier(_) => {
+                let token = self.bump();
+                node(SyntaxKind::Variable, vec![token])
+            }
+            TokenKind::OpenParen => {
+                let mut children = vec![self.bump(), self.expression()];
+                self.bump_if(&TokenKind::CloseParen, &mut children);
+                node(SyntaxKind::Grouping, children)
+            }
+            TokenKind::InterpolatedString(_) => {
+                let mut children = Vec::new();
+                while self.at(&TokenKind::InterpolatedString(String::new())) {
+                    children.push(self.bump());
+                    children.push(self.expression());
+                }
+                self.bump_if(&TokenKind::QuotedString(String::new()), &mut children);
+                node(SyntaxKind::Interpolation, children)
+            }
+            // Missing expression at the end of the file
+            TokenKind::Eof => node(SyntaxKind::Error, Vec::new()),
+            _ => {
+                let token = self.bump();
+                node(SyntaxKind::Error, vec![token])
+            }
+        }
+    }
+}
+
+fn node(kind: SyntaxKind, children: Vec<SyntaxElement>) -> SyntaxElement {
+    SyntaxElement::Node(SyntaxNode { kind, children })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{parse, SyntaxElement, SyntaxKind, SyntaxNode};
+    use crate::error::ErrorBag;
+
+    #[test]
+    fn cst_prints_the_source_unchanged() {
+        let sources = [
+            "/// Seconds in a day\nlet day = 60 * /* hours */ 60 * 24;   // trailing\n\n",
+            "print \"Hi ${ name }!\"\t;\r\nx = -(1 + 2) ; /* open /* nested */ still */",
+            "let = ) & \"unterminated",
+            "print r\"raw\\n\" == 0x_FF",
+        ];
+
+        for source in sources {
+            let mut error_bag = ErrorBag { errors: vec![] };
+            assert_eq!(parse(source, &mut error_bag).to_string(), source);
+        }
+    }
+
+    fn shape(node: &SyntaxNode) -> String {
+        let children: Vec<String> = node
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(child) => Some(shape(child)),
+                SyntaxElement::Token(_) => None,
+            })
+            .collect();
+        format!("{:?}({})", node.kind, children.join(" "))
+    }
+
+    #[test]
+    fn cst_follows_operator_precedence() {
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let root = parse("let x = 1 + 2 * -y;", &mut error_bag);
+
+        assert_eq!(root.kind, SyntaxKind::Root);
+        assert_eq!(
+            shape(&root),
+            "Root(LetStatement(Binary(Literal() Binary(Literal() Unary(Variable())))))"
+        );
+    }
+
+    #[test]
+    fn cst_limits_nesting_without_losing_text() {
+        let sources = [
+            format!("print {};", "(".repeat(1000)),
+            format!("print {}1;", "-".repeat(1000)),
+            format!("print 1{};", " + 1".repeat(10_000)),
+        ];
+
+        for source in sources {
+            let mut error_bag = ErrorBag { errors: vec![] };
+            let root = parse(&source, &mut error_bag);
+            assert_eq!(root.to_string(), source);
+        }
+
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let root = parse(&"(".repeat(1000), &mut error_bag);
+        let first = match &root.children[0] {
+            SyntaxElement::Node(statement) => shape(statement),
+            SyntaxElement::Token(_) => unreachable!(),
+        };
+        assert_eq!(first.matches("Grouping").count(), super::MAX_NESTING);
+        assert!(first.contains("Error()"));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{
+    bytecode::{Chunk, OpCode},
+    value::Value,
+};
+
+/// Renders every instruction of `chunk` as offset, source line, mnemonic
+/// and decoded operands, followed by the chunks of the functions it declares
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
+    let mut output = format!("== {name} ==\n");
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (line, next) = disassemble_instruction(chunk, offset);
+        output.push_str(&line);
+        output.push('\n');
+        offset = next;
+    }
+
+    for constant in &chunk.constant_pool {
+        if let Value::Prototype(prototype) = constant {
+            output.push('\n');
+            output.push_str(&disassemble_chunk(
+                &prototype.chunk,
+                prototype.name.as_str(),
+            ));
+        }
+    }
+
+    output
+}
+
+/// Renders the instruction at `offset` and returns the offset of the next one
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let position = match chunk.position(offset) {
+        Some(span) => format!("{:>4}:{:<3}", span.line, span.column),
+        None => format!("{:>4}:{:<3}", "?", "?"),
+    };
+    let prefix = format!("{offset:04} {position} ");
+
+    let Ok(op) = OpCode::try_from(chunk.code[offset]) else {
+        return (
+            format!("{prefix}Unknown opcode {}", chunk.code[offset]),
+            offset + 1,
+        );
+    };
+
+    let next = offset + 1 + op.operand_width();
+    if next > chunk.code.len() {
+        return (format!("{prefix}{:?} <truncated>", op), chunk.code.len());
+    }
+
+    let operands = match op {
+        OpCode::OpConstant => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            match chunk.constant_pool.get(index) {
+                Some(value) => format!("{index:>4} '{value}'"),
+                None => format!("{index:>4} <out of range>"),
+            }
+        }
+        OpCode::OpDefineGlobal | OpCode::OpGetGlobal | OpCode::OpSetGlobal => {
+            format!("{:>4}", chunk.read_u16(offset + 1))
+        }
+        OpCode::OpGetLocal
+        | OpCode::OpSetLocal
+        | OpCode::OpGetUpvalue
+        | OpCode::OpSetUpvalue
+        | OpCode::OpCall
+        | OpCode::OpInterpolate => format!("{:>4}", chunk.code[offset + 1]),
+        OpCode::OpClosure => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            match chunk.constant_pool.get(index) {
+                Some(Value::Prototype(prototype)) => {
+                    let captures: Vec<_> = prototype
+                        .upvalues
+                        .iter()
+                        .map(|source| match source.is_local {
+                            true => format!("local {}", source.index),
+                            false => format!("upvalue {}", source.index),
+                        })
+                        .collect();
+                    format!(
+                        "{index:>4} <fn {}> [{}]",
+                        prototype.name,
+                        captures.join(", ")
+                    )
+                }
+                _ => format!("{index:>4} <not a function>"),
+            }
+        }
+        OpCode::OpJump | OpCode::OpJumpIfFalse => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            format!("{offset:>4} -> {}", next + jump)
+        }
+        OpCode::OpLoop => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            format!("{offset:>4} -> {}", next.saturating_sub(jump))
+        }
+        _ => String::new(),
+    };
+
+    (
+        format!("{prefix}{:<16} {operands}", format!("{:?}", op))
+            .trim_end()
+            .to_string(),
+        next,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::disassemble_chunk;
+    use crate::test_support::compile;
+
+    #[test]
+    fn disassembler_decodes_operands() {
+        let chunk = compile("let x = 2;\nprint x;");
+
+        let listing = disassemble_chunk(&chunk, "script");
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!(lines[0], "== script ==");
+        assert_eq!(lines[1], "0000    1:9   OpConstant          0 '2'");
+        assert_eq!(lines[2], "0003    1:5   OpDefineGlobal      0");
+        assert_eq!(lines[3], "0006    2:7   OpGetGlobal         0");
+        assert_eq!(lines[4], "0009    2:1   OpPrint");
+    }
+}
@@ -1,7 +1,19 @@
+use crate::lint::Lint;
+
+#[derive(Debug)]
 pub enum LoxError {
     LexerError(String),
     ParseError(String),
+    ResolveError(String),
+    CompileError(String),
+    LoadError(String),
+    VerifyError(String),
+    EditError(String),
     RuntimeError(String),
+    Interrupted(String),
+    Warning(Lint, String),
+    /// A lint raised to `Level::Deny`
+    LintError(Lint, String),
 }
 
 pub struct ErrorBag {
@@ -10,34 +22,70 @@ pub struct ErrorBag {
 
 impl ErrorBag {
     pub fn drain(&mut self) {
-        if self.errors.is_empty() {
-            return;
-        }
+        let failed = self
+            .errors
+            .iter()
+            .any(|e| !matches!(e, LoxError::Warning(..)));
 
-        while let Some(e) = self.errors.pop() {
-            match e {
-                LoxError::LexerError(lxerr) => {
-                    eprintln!("{lxerr}");
-                }
-                _ => {}
-            }
+        for e in self.errors.drain(..) {
+            report(e);
         }
 
-        std::process::exit(1);
+        if failed {
+            std::process::exit(1);
+        }
     }
 }
 
-pub fn die(error: LoxError) {
+pub fn report(error: LoxError) {
     match error {
+        LoxError::LexerError(lxerr) => {
+            eprintln!("{lxerr}");
+        }
         LoxError::ParseError(message) => {
             eprint!("\x1b[31mParse Error: \x1b[0m");
             eprintln!("{message}");
         }
+        LoxError::ResolveError(message) => {
+            eprint!("\x1b[31mResolve Error: \x1b[0m");
+            eprintln!("{message}");
+        }
+        LoxError::CompileError(message) => {
+            eprint!("\x1b[31mCompile Error: \x1b[0m");
+            eprintln!("{message}");
+        }
+        LoxError::LoadError(message) => {
+            eprint!("\x1b[31mLoad Error: \x1b[0m");
+            eprintln!("{message}");
+        }
+        LoxError::VerifyError(message) => {
+            eprint!("\x1b[31mVerify Error: \x1b[0m");
+            eprintln!("{message}");
+        }
+        LoxError::EditError(message) => {
+            eprint!("\x1b[31mEdit Error: \x1b[0m");
+            eprintln!("{message}");
+        }
         LoxError::RuntimeError(message) => {
             eprint!("\x1b[31mRuntime Error: \x1b[0m");
             eprintln!("{message}");
         }
-        _ => {}
+        LoxError::Interrupted(message) => {
+            eprint!("\x1b[31mInterrupted: \x1b[0m");
+            eprintln!("{message}");
+        }
+        LoxError::Warning(lint, message) => {
+            eprint!("\x1b[33mWarning[{}]: \x1b[0m", lint.name());
+            eprintln!("{message}");
+        }
+        LoxError::LintError(lint, message) => {
+            eprint!("\x1b[31mError[{}]: \x1b[0m", lint.name());
+            eprintln!("{message}");
+        }
     }
+}
+
+pub fn die(error: LoxError) {
+    report(error);
     std::process::exit(1);
 }
@@ -0,0 +1,277 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
+};
+
+use crate::value::{Closure, Upvalue, Value};
+
+/// Bytes the VM may allocate before its first collection
+const INITIAL_THRESHOLD: usize = 1024 * 1024;
+/// The next collection waits until the surviving heap has grown this much
+const HEAP_GROW_FACTOR: usize = 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub collections: usize,
+    pub total_pause: Duration,
+    pub longest_pause: Duration,
+}
+
+enum Object {
+    Closure(Weak<Closure>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    String(Weak<str>),
+}
+
+struct Tracked {
+    object: Object,
+    size: usize,
+}
+
+/// Mark-and-sweep collector for the closures, upvalues and strings the
+/// `VM` allocates.
+///
+/// Values are reference counted, so acyclic garbage is freed as soon as it
+/// is dropped. The collector finds objects that are still alive but can no
+/// longer be reached from the roots, which means they only keep each other
+/// alive, and clears their references so the cycle falls apart.
+pub struct Heap {
+    objects: Vec<Tracked>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect before every allocation, used to shake out rooting bugs
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_THRESHOLD,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn track_closure(&mut self, closure: &Rc<Closure>) {
+        let size = std::mem::size_of::<Closure>()
+            + closure.upvalues.len() * std::mem::size_of::<Rc<RefCell<Upvalue>>>();
+        self.track(Object::Closure(Rc::downgrade(closure)), size);
+    }
+
+    pub fn track_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        let size = std::mem::size_of::<RefCell<Upvalue>>();
+        self.track(Object::Upvalue(Rc::downgrade(upvalue)), size);
+    }
+
+    pub fn track_string(&mut self, string: &Rc<str>) {
+        self.track(Object::String(Rc::downgrade(string)), string.len());
+    }
+
+    fn track(&mut self, object: Object, size: usize) {
+        self.objects.push(Tracked { object, size });
+        self.bytes_allocated += size;
+        self.stats.bytes_allocated += size;
+    }
+
+    /// Roots are everything the VM reaches directly: values on the stack and
+    /// in globals, closures of active frames and open upvalues
+    pub fn collect<'a>(
+        &mut self,
+        values: impl Iterator<Item = &'a Value>,
+        closures: impl Iterator<Item = &'a Rc<Closure>>,
+        upvalues: impl Iterator<Item = &'a Rc<RefCell<Upvalue>>>,
+    ) {
+        let start = Instant::now();
+
+        let mut marker = Marker::default();
+        for value in values {
+            marker.value(value);
+        }
+        for closure in closures {
+            marker.closure(closure);
+        }
+        for upvalue in upvalues {
+            marker.upvalue(upvalue);
+        }
+
+        let before = self.bytes_allocated;
+        let reached = marker.reached;
+        self.objects.retain(|tracked| {
+            // Garbage that is still alive is held by a cycle. Upvalues are the
+            // only mutable links the VM creates, so emptying the unreachable
+            // ones breaks every cycle and `Rc` frees the rest.
+            // Old contents are dropped after the borrow ends, since dropping
+            // them can free other objects.
+            let reachable = match &tracked.object {
+                Object::Closure(weak) => weak
+                    .upgrade()
+                    .map(|closure| reached.contains(&address(&closure))),
+                Object::Upvalue(weak) => weak.upgrade().map(|upvalue| {
+                    let reachable = reached.contains(&address(&upvalue));
+                    if !reachable {
+                        let old = upvalue.replace(Upvalue::Closed(Value::None));
+                        drop(old);
+                    }
+                    reachable
+                }),
+                Object::String(weak) => weak
+                    .upgrade()
+                    .map(|string| reached.contains(&address(&string))),
+            };
+
+            if reachable != Some(true) {
+                self.bytes_allocated -= tracked.size;
+            }
+            reachable == Some(true)
+        });
+
+        let pause = start.elapsed();
+        self.stats.collections += 1;
+        self.stats.bytes_freed += before - self.bytes_allocated;
+        self.stats.total_pause += pause;
+        self.stats.longest_pause = self.stats.longest_pause.max(pause);
+        self.next_gc = (self.bytes_allocated * HEAP_GROW_FACTOR).max(INITIAL_THRESHOLD);
+    }
+}
+
+fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+#[derive(Default)]
+struct Marker {
+    reached: HashSet<usize>,
+}
+
+impl Marker {
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Closure(closure) => self.closure(closure),
+            Value::String(string) => {
+                self.reached.insert(address(string));
+            }
+            _ => {}
+        }
+    }
+
+    fn closure(&mut self, closure: &Rc<Closure>) {
+        if self.reached.insert(address(closure)) {
+            for upvalue in &closure.upvalues {
+                self.upvalue(upvalue);
+            }
+        }
+    }
+
+    fn upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        if self.reached.insert(address(upvalue)) {
+            // Open upvalues point into the stack, which is a root already
+            if let Upvalue::Closed(ref value) = *upvalue.borrow() {
+                self.value(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::Heap;
+    use crate::{
+        bytecode::Chunk,
+        intern::Symbol,
+        value::{Closure, Prototype, Upvalue, Value},
+    };
+
+    /// A closure whose only upvalue holds the closure itself
+    fn self_referencing_closure(heap: &mut Heap) -> Rc<Closure> {
+        let upvalue = Rc::new(RefCell::new(Upvalue::Closed(Value::None)));
+        heap.track_upvalue(&upvalue);
+        let closure = Rc::new(Closure {
+            prototype: Rc::new(Prototype {
+                name: Symbol::intern("recurse"),
+                arity: 0,
+                upvalues: Vec::new(),
+                chunk: Chunk::new(),
+            }),
+            upvalues: vec![Rc::clone(&upvalue)],
+        });
+        heap.track_closure(&closure);
+        *upvalue.borrow_mut() = Upvalue::Closed(Value::Closure(Rc::clone(&closure)));
+        closure
+    }
+
+    #[test]
+    fn gc_frees_unreachable_cycles() {
+        let mut heap = Heap::new();
+        let garbage = Rc::downgrade(&self_referencing_closure(&mut heap));
+        assert!(garbage.upgrade().is_some());
+
+        heap.collect(std::iter::empty(), std::iter::empty(), std::iter::empty());
+
+        assert!(garbage.upgrade().is_none());
+        assert_eq!(heap.stats().collections, 1);
+        assert_eq!(heap.stats().bytes_freed, heap.stats().bytes_allocated);
+    }
+
+    #[test]
+    fn gc_keeps_rooted_cycles() {
+        let mut heap = Heap::new();
+        heap.set_stress(true);
+        let root = Value::Closure(self_referencing_closure(&mut heap));
+
+        assert!(heap.should_collect());
+        heap.collect([&root].into_iter(), std::iter::empty(), std::iter::empty());
+
+        let Value::Closure(ref closure) = root else {
+            unreachable!()
+        };
+        assert!(matches!(
+            *closure.upvalues[0].borrow(),
+            Upvalue::Closed(Value::Closure(_))
+        ));
+        assert_eq!(heap.stats().bytes_freed, 0);
+    }
+
+    #[test]
+    fn gc_accounts_for_strings() {
+        let mut heap = Heap::new();
+        let kept: Rc<str> = Rc::from("kept");
+        let dropped: Rc<str> = Rc::from("dropped");
+        heap.track_string(&kept);
+        heap.track_string(&dropped);
+        drop(dropped);
+
+        let root = Value::String(kept);
+        heap.collect([&root].into_iter(), std::iter::empty(), std::iter::empty());
+
+        assert_eq!(heap.stats().bytes_allocated, "kept".len() + "dropped".len());
+        assert_eq!(heap.stats().bytes_freed, "dropped".len());
+    }
+}
@@ -0,0 +1,415 @@
+use std::ops::Range;
+
+use crate::{
+    cst::{self, CstParser, SyntaxElement, SyntaxNode, SyntaxToken, TriviaLexer},
+    error::{ErrorBag, LoxError},
+    lexer::{Lexer, Position, TokenKind},
+};
+
+/// Replaces the bytes in `range` of the current text with `replacement`
+#[derive(Debug, Clone)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+/// Source text kept together with its syntax tree so that editors can
+/// apply keystrokes without re-lexing and re-parsing the whole file.
+/// Only the tokens around an edit are lexed again, and only the top-level
+/// statements that could have changed are parsed again; everything else
+/// is reused with its positions moved. The result is always the tree that
+/// `cst::parse` builds for the new text.
+pub struct Document {
+    pub source: String,
+    /// Top-level statements followed by the `Eof` token
+    pub root: SyntaxNode,
+    tokens: Vec<SyntaxToken>,
+    /// Whether the lexer was outside every `${` right before each token,
+    /// which is where lexing can start over
+    top_level: Vec<bool>,
+    /// Index in `tokens` of the first token of every statement
+    starts: Vec<usize>,
+}
+
+impl Document {
+    pub fn new(source: String, error_bag: &mut ErrorBag) -> Self {
+        let mut lexer = TriviaLexer::new(&source, Lexer::new(&source, error_bag));
+        let (tokens, top_level) = lex(&mut lexer, |_| false);
+
+        let mut document = Self {
+            root: SyntaxNode {
+                kind: cst::SyntaxKind::Root,
+                children: Vec::new(),
+            },
+            source,
+            tokens: Vec::new(),
+            top_level,
+            starts: Vec::new(),
+        };
+        let (statements, starts, _, tokens) = parse_statements(tokens, 0, |_| false);
+        document.root.children = statements;
+        document.root.children.push(eof(&tokens));
+        document.starts = starts;
+        document.tokens = tokens;
+        document
+    }
+
+    /// Applies `edit` and returns the indices of the top-level statements
+    /// that were parsed again. Lexer errors are only reported for the text
+    /// that was lexed again. A range outside the text or splitting a
+    /// character is an error, and the document is left unchanged.
+    pub fn edit(
+        &mut self,
+        edit: &TextEdit,
+        error_bag: &mut ErrorBag,
+    ) -> Result<Range<usize>, LoxError> {
+        let TextEdit { range, replacement } = edit;
+        if range.start > range.end || range.end > self.source.len() {
+            return Err(LoxError::EditError(format!(
+                "Range \x1b[32m{range:?}\x1b[0m is outside the {} bytes of the document",
+                self.source.len()
+            )));
+        }
+        if !self.source.is_char_boundary(range.start) || !self.source.is_char_boundary(range.end) {
+            return Err(LoxError::EditError(format!(
+                "Range \x1b[32m{range:?}\x1b[0m splits a character"
+            )));
+        }
+        let delta = replacement.len() as isize - range.len() as isize;
+        let edited_end = range.start + replacement.len();
+        let old_eof = self.tokens.len() - 1;
+
+        // Lexing starts over after the last token that ends before the edit.
+        // The character following a token decides where it ends, so that
+        // token has to end strictly before the edited text.
+        let mut restart = self
+            .tokens
+            .partition_point(|token| end_of(token) < range.start);
+        while !self.top_level[restart] {
+            restart -= 1;
+        }
+        let start = match restart {
+            0 => Position::new(1, 1),
+            _ => {
+                let previous = &self.tokens[restart - 1];
+                previous.span.advanced_by(&previous.text)
+            }
+        };
+
+        self.source.replace_range(range.clone(), replacement);
+
+        // Lexing stops as soon as it lines up with an old token boundary
+        // past the edit, where the old lexer was in the same state
+        let old_tokens = &self.tokens;
+        let old_top_level = &self.top_level;
+        let mut lexer =
+            TriviaLexer::new(&self.source, Lexer::resume(&self.source, start, error_bag));
+        let mut resync = None;
+        let (fresh, fresh_top_level) = lex(&mut lexer, |lexer| {
+            let position = lexer.position();
+            if position.offset < edited_end || lexer.in_interpolation() {
+                return false;
+            }
+            let old_offset = position.offset.wrapping_add_signed(-delta);
+            let before = old_tokens.partition_point(|token| end_of(token) < old_offset);
+            let next = before + 1;
+            if before < old_tokens.len()
+                && end_of(&old_tokens[before]) == old_offset
+                && next < old_tokens.len()
+                && old_top_level[next]
+            {
+                let old = &old_tokens[before];
+                resync = Some((next, old.span.advanced_by(&old.text), position.clone()));
+                return true;
+            }
+            false
+        });
+
+        // Splice the fresh tokens in, moving the reused ones after them
+        let fresh_count = fresh.len();
+        let mut tokens = std::mem::take(&mut self.tokens);
+        let mut top_level = std::mem::take(&mut self.top_level);
+        let (mut suffix, suffix_top_level) = match &resync {
+            Some((next, ..)) => (tokens.split_off(*next), top_level.split_off(*next)),
+            None => (Vec::new(), Vec::new()),
+        };
+        tokens.truncate(restart);
+        top_level.truncate(restart);
+        if let Some((_, from, to)) = &resync {
+            for token in &mut suffix {
+                shift(token, from, to);
+            }
+        }
+        tokens.extend(fresh);
+        tokens.extend(suffix);
+        top_level.extend(fresh_top_level);
+        top_level.extend(suffix_top_level);
+        self.top_level = top_level;
+
+        // A statement is kept when neither its tokens nor the token the
+        // parser looked at after it were lexed again
+        let old_starts = std::mem::take(&mut self.starts);
+        let kept = (0..old_starts.len())
+            .take_while(|&index| old_starts.get(index + 1).copied().unwrap_or(old_eof) < restart)
+            .count();
+        let cursor = old_starts.get(kept).copied().unwrap_or(0);
+
+        // Parsing stops at the start of an old statement past the resync
+        // point, since everything from there on reads the same tokens
+        let suffix_start = restart + fresh_count;
+        let reusable = |cursor: usize| {
+            let (next, ..) = resync.as_ref()?;
+            let old_index = cursor.checked_sub(suffix_start)? + next;
+            old_starts.binary_search(&old_index).ok()
+        };
+        let (fresh_statements, fresh_starts, cursor, tokens) =
+            parse_statements(tokens, cursor, |cursor| reusable(cursor).is_some());
+
+        let mut children = std::mem::take(&mut self.root.children);
+        children.pop();
+        let mut reused = Vec::new();
+        let mut starts = old_starts[..kept].to_vec();
+        starts.extend(fresh_starts);
+        if let (Some(first), Some((next, from, to))) = (reusable(cursor), &resync) {
+            let moved = suffix_start as isize - *next as isize;
+            starts.extend(
+                old_starts[first..]
+                    .iter()
+                    .map(|&index| index.wrapping_add_signed(moved)),
+            );
+            reused = children.split_off(first);
+            for statement in &mut reused {
+                shift_element(statement, from, to);
+            }
+        }
+
+        let parsed = kept..kept + fresh_statements.len();
+        children.truncate(kept);
+        children.extend(fresh_statements);
+        children.extend(reused);
+        children.push(eof(&tokens));
+
+        self.root.children = children;
+        self.starts = starts;
+        self.tokens = tokens;
+        Ok(parsed)
+    }
+}
+
+fn end_of(token: &SyntaxToken) -> usize {
+    token.span.offset + token.text.len()
+}
+
+fn eof(tokens: &[SyntaxToken]) -> SyntaxElement {
+    SyntaxElement::Token(tokens[tokens.len() - 1].clone())
+}
+
+/// Moves a token found after `from` in the old text so that `from` lands
+/// on `to`. Only tokens on the line of `from` change columns.
+fn shift(token: &mut SyntaxToken, from: &Position, to: &Position) {
+    let span = &mut token.span;
+    if span.line == from.line {
+        span.column = span.column + to.column - from.column;
+        span.utf16_column = span.utf16_column + to.utf16_column - from.utf16_column;
+    }
+    span.line = span.line + to.line - from.line;
+    span.offset = span.offset + to.offset - from.offset;
+}
+
+/// Shifts every token under `element`, without recursing since long
+/// operator chains build deep trees
+fn shift_element(element: &mut SyntaxElement, from: &Position, to: &Position) {
+    let mut pending = vec![element];
+    while let Some(element) = pending.pop() {
+        match element {
+            SyntaxElement::Token(token) => shift(token, from, to),
+            SyntaxElement::Node(node) => pending.extend(&mut node.children),
+        }
+    }
+}
+
+/// Collects tokens until the end of the file or until `stop` returns true
+/// between two tokens
+fn lex(
+    lexer: &mut TriviaLexer,
+    mut stop: impl FnMut(&TriviaLexer) -> bool,
+) -> (Vec<SyntaxToken>, Vec<bool>) {
+    let mut tokens = Vec::new();
+    let mut top_level = Vec::new();
+
+    while !stop(lexer) {
+        top_level.push(!lexer.in_interpolation());
+        let Some(token) = lexer.next() else {
+            break;
+        };
+        let eof = matches!(token.kind, TokenKind::Eof);
+        tokens.push(token);
+        if eof {
+            break;
+        }
+    }
+
+    (tokens, top_level)
+}
+
+/// Parses statements from `cursor` until the end of the file or until
+/// `stop` returns true at the start of a statement. Returns the statements,
+/// the index of their first tokens, where parsing stopped and the tokens.
+#[allow(clippy::type_complexity)]
+fn parse_statements(
+    tokens: Vec<SyntaxToken>,
+    cursor: usize,
+    stop: impl Fn(usize) -> bool,
+) -> (Vec<SyntaxElement>, Vec<usize>, usize, Vec<SyntaxToken>) {
+    let mut parser = CstParser::new(tokens, cursor);
+    let mut statements = Vec::new();
+    let mut starts = Vec::new();
+
+    while !parser.at(&TokenKind::Eof) && !stop(parser.cursor) {
+        starts.push(parser.cursor);
+        statements.push(parser.statement());
+    }
+
+    (statements, starts, parser.cursor, parser.tokens)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Document, TextEdit};
+    use crate::{
+        cst,
+        error::{ErrorBag, LoxError},
+        test_support::Xorshift,
+    };
+
+    fn edit(range: std::ops::Range<usize>, replacement: &str) -> TextEdit {
+        TextEdit {
+            range,
+            replacement: replacement.to_string(),
+        }
+    }
+
+    /// Applies `edit` to `document` and checks it against parsing the new
+    /// text from scratch
+    fn apply(document: &mut Document, edit: &TextEdit) -> std::ops::Range<usize> {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let parsed = document.edit(edit, &mut error_bag).unwrap();
+
+        let full = cst::parse(&document.source, &mut error_bag);
+        assert_eq!(format!("{:?}", document.root), format!("{full:?}"));
+        assert_eq!(
+            format!("{:?}", document.tokens),
+            format!(
+                "{:?}",
+                cst::tokens_with_trivia(&document.source, &mut error_bag)
+            )
+        );
+        assert_eq!(document.root.to_string(), document.source);
+        parsed
+    }
+
+    #[test]
+    fn incremental_reparse_only_touches_edited_statements() {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let source = "let a = 1;\nlet b = a + 2; print b;\n// done\nprint a;\n";
+        let mut document = Document::new(source.to_string(), &mut error_bag);
+
+        // `2` becomes `20`, with the statements after it moved right
+        assert_eq!(apply(&mut document, &edit(23..24, "20")), 1..2);
+        // New lines push the rest of the file down. The statement before
+        // them is parsed again too, as the parser looked at the token after it.
+        assert_eq!(apply(&mut document, &edit(11..11, "\n\n")), 0..2);
+        // Editing the comment changes the trivia of the last statement
+        assert_eq!(apply(&mut document, &edit(41..45, "finished")), 2..4);
+        assert_eq!(
+            document.source,
+            "let a = 1;\n\n\nlet b = a + 20; print b;\n// finished\nprint a;\n"
+        );
+    }
+
+    #[test]
+    fn incremental_reparse_matches_full_reparse() {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let mut document = Document::new(
+            "let s = \"a${x}b\";\nprint s == \"é\"; x = -(1 + 2)\n".to_string(),
+            &mut error_bag,
+        );
+
+        // Comments and strings that swallow the rest of the file, and
+        // closing them again
+        apply(&mut document, &edit(0..0, "/* "));
+        apply(&mut document, &edit(3..3, "*/"));
+        apply(&mut document, &edit(14..14, "\""));
+        apply(&mut document, &edit(14..15, ""));
+        // Inside an interpolation, then leaving it unterminated
+        apply(&mut document, &edit(18..19, "y + 1"));
+        apply(&mut document, &edit(23..24, ""));
+        apply(&mut document, &edit(23..23, "}"));
+        // Gluing tokens together and splitting them
+        apply(&mut document, &edit(2..3, ""));
+        apply(&mut document, &edit(2..2, " "));
+        apply(&mut document, &edit(29..30, ""));
+        let end = document.source.len();
+        apply(&mut document, &edit(end..end, "let"));
+        apply(&mut document, &edit(0..end + 3, ""));
+        apply(&mut document, &edit(0..0, "print 1"));
+    }
+
+    #[test]
+    fn incremental_reparse_survives_random_edits() {
+        let fragments = [
+            " ", "\n", ";", "let ", "x", "=", "==", "1", "0x1F", "+", "*", "(", ")", "\"", "${",
+            "}", "{", "//", "/*", "*/", "print ", "é", "r\"", "\\", "-", "!",
+        ];
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let mut document = Document::new(
+            "let x = 1;\nprint \"${x}\";\nx = x * 2; // twice\n".to_string(),
+            &mut error_bag,
+        );
+
+        let mut rng = Xorshift::new(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..500 {
+            let source = &document.source;
+            let mut start = rng.below(source.len() + 1);
+            while !source.is_char_boundary(start) {
+                start -= 1;
+            }
+            let mut end = (start + rng.below(5)).min(source.len());
+            while !source.is_char_boundary(end) {
+                end += 1;
+            }
+            let replacement = match rng.below(4) {
+                0 => "",
+                _ => fragments[rng.below(fragments.len())],
+            };
+            apply(&mut document, &edit(start..end, replacement));
+        }
+    }
+
+    #[test]
+    fn incremental_edit_rejects_ranges_outside_the_text() {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let mut document = Document::new("let x = 1; // é".to_string(), &mut error_bag);
+
+        // Past the end, reversed, and inside the two bytes of `é`
+        #[allow(clippy::reversed_empty_ranges)]
+        for range in [5..100, 7..5, 16..17, 15..16] {
+            let result = document.edit(&edit(range.clone(), "2"), &mut error_bag);
+            assert!(matches!(result, Err(LoxError::EditError(_))), "{range:?}");
+        }
+        assert_eq!(document.source, "let x = 1; // é");
+        assert_eq!(document.root.to_string(), document.source);
+    }
+
+    #[test]
+    fn incremental_reparse_survives_deep_nesting() {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let source = format!("print {};", "(".repeat(1000));
+        let mut document = Document::new(source, &mut error_bag);
+
+        apply(&mut document, &edit(0..0, "let x = 1;"));
+        apply(&mut document, &edit(500..501, "-"));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{LazyLock, PoisonError, RwLock},
+};
+
+/// Handle to a string in the interner. Two symbols are equal exactly when
+/// their strings are, so comparing and hashing them never touches the text.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    symbols: HashMap<&'static str, Symbol>,
+    strings: Vec<&'static str>,
+}
+
+/// Shared by every thread, so a program parsed on one thread can run on
+/// another. Interned strings live until the process exits.
+static INTERNER: LazyLock<RwLock<Interner>> = LazyLock::new(Default::default);
+
+impl Symbol {
+    pub fn intern(string: &str) -> Self {
+        if let Some(&symbol) = INTERNER
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .symbols
+            .get(string)
+        {
+            return symbol;
+        }
+
+        // Nothing panics while the lock is held, so a poisoned interner is
+        // still consistent
+        let mut interner = INTERNER.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&symbol) = interner.symbols.get(string) {
+            return symbol;
+        }
+
+        let symbol = Symbol(interner.strings.len() as u32);
+        let string: &'static str = Box::leak(Box::from(string));
+        interner.strings.push(string);
+        interner.symbols.insert(string, symbol);
+        symbol
+    }
+
+    /// The interned text, shared with every other user of this symbol
+    pub fn as_str(&self) -> &'static str {
+        INTERNER
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .strings[self.0 as usize]
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Symbol;
+
+    #[test]
+    fn interning_returns_one_handle_per_string() {
+        let first = Symbol::intern("counter");
+        let second = Symbol::intern(&String::from("counter"));
+
+        assert_eq!(first, second);
+        assert_ne!(first, Symbol::intern("count"));
+        assert!(std::ptr::eq(first.as_str(), second.as_str()));
+        assert_eq!(format!("{first} {first:?}"), "counter \"counter\"");
+    }
+
+    #[test]
+    fn symbols_resolve_on_other_threads() {
+        let local = Symbol::intern("made on the main thread");
+        let (remote, text) =
+            std::thread::spawn(move || (Symbol::intern("made on another thread"), local.as_str()))
+                .join()
+                .unwrap();
+
+        assert_eq!(text, "made on the main thread");
+        assert_eq!(remote.as_str(), "made on another thread");
+        assert_eq!(remote, Symbol::intern("made on another thread"));
+    }
+}
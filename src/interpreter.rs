@@ -1,77 +1,110 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 
-#[allow(unused)]
-use crate::ast::{Expression, LiteralKind};
 use crate::{
-    ast::{BinaryExpr, Statement, UnaryExpr},
+    ast::{BinaryExpr, Expression, Statement, UnaryExpr, Variable},
+    cancel::CancellationToken,
     error::LoxError,
     lexer::TokenKind,
+    value::Value,
 };
 
-type Environment = HashMap<String, Box<Expression>>;
+/// Variables are read and written through the slots assigned by the
+/// resolver instead of hashing their names. The grammar only has a global
+/// scope so far, so every slot indexes the globals table.
+#[derive(Default)]
+pub struct Environment {
+    globals: Vec<Value>,
+}
+
+impl Environment {
+    pub fn get(&self, variable: &Variable) -> Option<&Value> {
+        variable.slot.and_then(|slot| self.globals.get(slot.index))
+    }
+
+    pub fn set(&mut self, variable: &Variable, value: Value) -> Result<(), LoxError> {
+        let Some(slot) = variable.slot else {
+            return Err(LoxError::RuntimeError(format!(
+                "Use of unresolved identifier \x1b[32m{}\x1b[0m at line {} column {}",
+                variable.name, variable.span.line, variable.span.column
+            )));
+        };
+
+        if slot.index >= self.globals.len() {
+            self.globals.resize(slot.index + 1, Value::None);
+        }
+        self.globals[slot.index] = value;
+        Ok(())
+    }
+}
 
 pub struct Interpreter {
     env: Environment,
+    cancel: CancellationToken,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Self {
+        Self::with_cancellation(CancellationToken::new())
+    }
+
+    pub fn with_cancellation(cancel: CancellationToken) -> Self {
         Self {
-            env: Environment::new(),
+            env: Environment::default(),
+            cancel,
         }
     }
 
-    pub fn execute(&mut self, statements: Vec<Box<Statement>>) {
+    pub fn execute(&mut self, statements: Vec<Box<Statement>>) -> Result<(), LoxError> {
         for statement in statements {
+            self.cancel.check()?;
             match *statement {
                 Statement::Expr(expr) => {
-                    match *expr {
-                        Expression::Assign(ref varname, exprval) => {
-                            // NOTE: Assign value must be first be evaluated to avoid infinite recursion
-                            let value = exprval.eval(&self.env);
-                            self.env
-                                .insert(varname.clone(), Box::new(Expression::Literal(value)));
-                        }
-                        _ => {
-                            expr.eval(&self.env);
-                        }
-                    };
+                    expr.eval(&mut self.env)?;
                 }
-                Statement::Print(expr) => {
-                    let value = expr.eval(&self.env);
+                Statement::Print(expr, _) => {
+                    let value = expr.eval(&mut self.env)?;
                     println!("{value}")
                 }
-                Statement::Let(varname, value) => {
-                    self.env.insert(
-                        varname.clone(),
-                        Box::new(Expression::Literal(value.eval(&self.env))),
-                    );
+                Statement::Let(variable, value, _) => {
+                    let value = value.eval(&mut self.env)?;
+                    self.env.set(&variable, value)?;
                 }
             };
         }
+
+        Ok(())
     }
 }
 
 macro_rules! numeric_binary_op (
-    ($op:tt, $lhs:ident, $rhs:ident) => (
+    ($op:tt, $checked:ident, $lhs:ident, $rhs:ident) => (
         match (&$lhs, &$rhs) {
-            (LiteralKind::Integer(ilhs), LiteralKind::Integer(irhs)) => {
-                LiteralKind::Integer(ilhs $op irhs)
+            (Value::Integer(ilhs), Value::Integer(irhs)) => match ilhs.$checked(*irhs) {
+                Some(result) => Value::Integer(result),
+                None if *irhs == 0 => {
+                    return Err("Integer division by zero".to_string());
+                },
+                None => {
+                    return Err(format!("Integer overflow in \x1b[34m{ilhs} {} {irhs}\x1b[0m", stringify!($op)));
+                },
             },
-            (LiteralKind::Integer(ilhs), LiteralKind::Decimal(drhs)) => {
-                LiteralKind::Decimal(*ilhs as f64 $op drhs)
+            (Value::Integer(ilhs), Value::Decimal(drhs)) => {
+                Value::Decimal(*ilhs as f64 $op drhs)
             },
-            (LiteralKind::Decimal(dlhs), LiteralKind::Integer(irhs)) => {
-                LiteralKind::Decimal(dlhs $op *irhs as f64)
+            (Value::Decimal(dlhs), Value::Integer(irhs)) => {
+                Value::Decimal(dlhs $op *irhs as f64)
             },
-            (LiteralKind::Decimal(dlhs), LiteralKind::Decimal(drhs)) => {
-                LiteralKind::Decimal(dlhs $op drhs)
+            (Value::Decimal(dlhs), Value::Decimal(drhs)) => {
+                Value::Decimal(dlhs $op drhs)
             },
             _ => {
-                crate::error::die(LoxError::RuntimeError(
-                    format!("Binary expression not allowed between those two types \x1b[34m{:?}\x1b[0m and \x1b[34m{:?}\x1b[0m", $lhs, $rhs))
-                );
-                unreachable!("")
+                return Err(format!("Binary expression not allowed between those two types \x1b[34m{:?}\x1b[0m and \x1b[34m{:?}\x1b[0m", $lhs, $rhs));
             },
         }
     );
@@ -80,137 +113,180 @@ macro_rules! numeric_binary_op (
 macro_rules! comparison_op (
     ($op:tt, $lhs:ident, $rhs:ident) => (
         match (&$lhs, &$rhs) {
-            (LiteralKind::Integer(ilhs), LiteralKind::Integer(irhs)) => {
-                LiteralKind::Boolean(ilhs $op irhs)
+            (Value::Integer(ilhs), Value::Integer(irhs)) => {
+                Value::Boolean(ilhs $op irhs)
             },
-            (LiteralKind::Integer(ilhs), LiteralKind::Decimal(drhs)) => {
-                LiteralKind::Boolean((*ilhs as f64) $op *drhs)
+            (Value::Integer(ilhs), Value::Decimal(drhs)) => {
+                Value::Boolean((*ilhs as f64) $op *drhs)
             },
-            (LiteralKind::Decimal(dlhs), LiteralKind::Integer(irhs)) => {
-                LiteralKind::Boolean(dlhs $op &(*irhs as f64))
+            (Value::Decimal(dlhs), Value::Integer(irhs)) => {
+                Value::Boolean(dlhs $op &(*irhs as f64))
             },
-            (LiteralKind::Decimal(dlhs), LiteralKind::Decimal(drhs)) => {
-                LiteralKind::Boolean(dlhs $op drhs)
+            (Value::Decimal(dlhs), Value::Decimal(drhs)) => {
+                Value::Boolean(dlhs $op drhs)
             },
-            (LiteralKind::Boolean(blhs), LiteralKind::Boolean(brhs)) => {
-                LiteralKind::Boolean(blhs $op brhs)
+            (Value::Boolean(blhs), Value::Boolean(brhs)) => {
+                Value::Boolean(blhs $op brhs)
             }
             _ => {
-                crate::error::die(LoxError::RuntimeError(
-                    format!("Comparison expression not allowed between those two types \x1b[34m{:?}\x1b[0m and \x1b[34m{:?}\x1b[0m", $lhs, $rhs))
-                );
-                unreachable!()
+                return Err(format!("Comparison expression not allowed between those two types \x1b[34m{:?}\x1b[0m and \x1b[34m{:?}\x1b[0m", $lhs, $rhs));
             },
         }
     );
 );
 
+/// Semantics of every binary operator, shared by the tree-walker and the `VM`
+pub fn binary_op(operator: &TokenKind, lhs: Value, rhs: Value) -> Result<Value, String> {
+    let value = match operator {
+        TokenKind::Plus => numeric_binary_op!(+, checked_add, lhs, rhs),
+        TokenKind::Minus => numeric_binary_op!(-, checked_sub, lhs, rhs),
+        TokenKind::Asterisk => numeric_binary_op!(*, checked_mul, lhs, rhs),
+        TokenKind::ForwardSlash => numeric_binary_op!(/, checked_div, lhs, rhs),
+        TokenKind::Percentage => numeric_binary_op!(%, checked_rem, lhs, rhs),
+        TokenKind::GreaterThan => comparison_op!(>, lhs, rhs),
+        TokenKind::GreaterEqual => comparison_op!(>=, lhs, rhs),
+        TokenKind::LessThan => comparison_op!(<, lhs, rhs),
+        TokenKind::LessEqual => comparison_op!(<=, lhs, rhs),
+        TokenKind::Equal => match (&lhs, &rhs) {
+            (Value::None, Value::None) => Value::Boolean(true),
+            (Value::None, _) => Value::Boolean(false),
+            (_, Value::None) => Value::Boolean(false),
+            _ => comparison_op!(==, lhs, rhs),
+        },
+        TokenKind::NotEqual => match (&lhs, &rhs) {
+            (Value::None, Value::None) => Value::Boolean(false),
+            (Value::None, _) => Value::Boolean(true),
+            (_, Value::None) => Value::Boolean(true),
+            _ => comparison_op!(!=, lhs, rhs),
+        },
+        other => {
+            return Err(format!(
+                "Binary expression should not contain operator {}",
+                other
+            ))
+        }
+    };
+
+    Ok(value)
+}
+
+/// Semantics of every unary operator, shared by the tree-walker and the `VM`
+pub fn unary_op(operator: &TokenKind, rhs: Value) -> Result<Value, String> {
+    match operator {
+        TokenKind::Minus => match rhs {
+            Value::Integer(i) => match i.checked_neg() {
+                Some(negated) => Ok(Value::Integer(negated)),
+                None => Err(format!("Integer overflow in \x1b[34m-({i})\x1b[0m")),
+            },
+            Value::Decimal(d) => Ok(Value::Decimal(-d)),
+            _ => Err(format!(
+                "Unary expression {} not allowed with operand \x1b[34m{:?}\x1b[0m",
+                operator, rhs
+            )),
+        },
+        TokenKind::Bang => match rhs {
+            Value::Boolean(b) => Ok(Value::Boolean(!b)),
+            Value::None => Ok(Value::Boolean(true)),
+            _ => Err(format!(
+                "Unary expression {} not allowed to this operand \x1b[34m{:?}\x1b[0m",
+                operator, rhs
+            )),
+        },
+        other => Err(format!(
+            "Unary expression should not contain operator {}",
+            other
+        )),
+    }
+}
+
 pub trait Eval {
-    fn eval(&self, env: &Environment) -> LiteralKind;
+    fn eval(&self, env: &mut Environment) -> Result<Value, LoxError>;
 }
 
 impl Eval for BinaryExpr {
-    fn eval(&self, env: &Environment) -> LiteralKind {
-        let lhs = self.lhs.eval(env);
-        let rhs = self.rhs.eval(env);
-
-        match self.operator.kind {
-            TokenKind::Plus => numeric_binary_op!(+, lhs, rhs),
-            TokenKind::Minus => numeric_binary_op!(-, lhs, rhs),
-            TokenKind::Asterisk => numeric_binary_op!(*, lhs, rhs),
-            TokenKind::ForwardSlash => numeric_binary_op!(/, lhs, rhs),
-            TokenKind::Percentage => numeric_binary_op!(%, lhs, rhs),
-            TokenKind::GreaterThan => comparison_op!(>, lhs, rhs),
-            TokenKind::GreaterEqual => comparison_op!(>=, lhs, rhs),
-            TokenKind::LessThan => comparison_op!(<, lhs, rhs),
-            TokenKind::LessEqual => comparison_op!(<=, lhs, rhs),
-            TokenKind::Equal => match (&lhs, &rhs) {
-                (LiteralKind::None, LiteralKind::None) => LiteralKind::Boolean(true),
-                (LiteralKind::None, _) => LiteralKind::Boolean(false),
-                (_, LiteralKind::None) => LiteralKind::Boolean(false),
-                _ => comparison_op!(==, lhs, rhs),
-            },
-            TokenKind::NotEqual => match (&lhs, &rhs) {
-                (LiteralKind::None, LiteralKind::None) => LiteralKind::Boolean(false),
-                (LiteralKind::None, _) => LiteralKind::Boolean(true),
-                (_, LiteralKind::None) => LiteralKind::Boolean(true),
-                _ => comparison_op!(!=, lhs, rhs),
-            },
-            _ => {
-                crate::error::die(LoxError::RuntimeError(format!(
-                    "Binary expression should not contain operator {}",
-                    self.operator.clone().kind
-                )));
-                unreachable!()
-            }
-        }
+    fn eval(&self, env: &mut Environment) -> Result<Value, LoxError> {
+        let lhs = self.lhs.eval(env)?;
+        let rhs = self.rhs.eval(env)?;
+
+        binary_op(&self.operator.kind, lhs, rhs).map_err(|message| {
+            LoxError::RuntimeError(format!(
+                "{message} at line {} column {}",
+                self.operator.span.line, self.operator.span.column
+            ))
+        })
     }
 }
 
 impl Eval for UnaryExpr {
-    fn eval(&self, env: &Environment) -> LiteralKind {
-        let rhs = self.rhs.eval(env);
-
-        match self.operator.kind {
-            TokenKind::Minus => match rhs {
-                LiteralKind::Integer(i) => LiteralKind::Integer(-i),
-                LiteralKind::Decimal(d) => LiteralKind::Decimal(-d),
-                _ => {
-                    crate::error::die(LoxError::RuntimeError(format!(
-                        "Unary expression {} not allowed with operand \x1b[34m{:?}\x1b[0m at line {}",
-                        self.operator.clone().kind,
-                        rhs,
-                        self.operator.span.line
-                    )));
-                    unreachable!()
-                }
-            },
-            TokenKind::Bang => match rhs {
-                LiteralKind::Boolean(b) => LiteralKind::Boolean(!b),
-                LiteralKind::None => LiteralKind::Boolean(true),
-                _ => {
-                    crate::error::die(LoxError::RuntimeError(format!(
-                        "Unary expression {} not allowed to this operand \x1b[34m{:?}\x1b[0m at line {}",
-                        self.operator.clone().kind,
-                        rhs,
-                        self.operator.span.line
-                    )));
-                    unreachable!()
-                }
-            },
-            _ => {
-                crate::error::die(LoxError::RuntimeError(format!(
-                    "Unary expression should not contain operator {}",
-                    self.operator.clone().kind
-                )));
-                unreachable!()
-            }
-        }
+    fn eval(&self, env: &mut Environment) -> Result<Value, LoxError> {
+        let rhs = self.rhs.eval(env)?;
+
+        unary_op(&self.operator.kind, rhs).map_err(|message| {
+            LoxError::RuntimeError(format!(
+                "{message} at line {} column {}",
+                self.operator.span.line, self.operator.span.column
+            ))
+        })
     }
 }
 
 impl Eval for Expression {
-    fn eval(&self, env: &Environment) -> LiteralKind {
+    fn eval(&self, env: &mut Environment) -> Result<Value, LoxError> {
         match self {
             Self::Binary(expr) => expr.eval(env),
             Self::Unary(expr) => expr.eval(env),
             Self::Grouping(expr) => expr.eval(env),
-            Self::Literal(expr) => match expr {
-                &LiteralKind::Identifier(ref s) => {
-                    let val = env.get(s).cloned();
-                    match val {
-                        Some(expr) => expr.eval(env),
-                        _ => {
-                            crate::error::die(LoxError::RuntimeError(format!(
-                                "Use of undeclared identifier \x1b[32m{s}\x1b[0m"
-                            )));
-                            unreachable!()
-                        }
-                    }
-                }
-                _ => expr.clone(),
+            Self::Literal(literal, _) => Ok(Value::from(literal)),
+            Self::Variable(variable) => match env.get(variable) {
+                Some(value) => Ok(value.clone()),
+                _ => Err(LoxError::RuntimeError(format!(
+                    "Use of undeclared identifier \x1b[32m{}\x1b[0m at line {} column {}",
+                    variable.name, variable.span.line, variable.span.column
+                ))),
             },
-            _ => unreachable!("Assign expressions cannot be evaluated here"),
+            Self::Interpolation(parts) => {
+                let mut string = String::new();
+                for part in parts {
+                    string.push_str(&part.eval(env)?.to_string());
+                }
+                Ok(Value::String(Rc::from(string)))
+            }
+            // An assignment evaluates to the assigned value, like it does
+            // on the VM
+            Self::Assign(variable, value) => {
+                let value = value.eval(env)?;
+                env.set(variable, value.clone())?;
+                Ok(value)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::Interpreter;
+    use crate::{
+        test_support::{compile, resolve},
+        vm::{InterpretResult, VM},
+    };
+
+    #[test]
+    fn nested_assignments_evaluate_the_same_on_both_engines() {
+        let program = "let x = 0; print (x = 1;); let y = (x = 5;) + 1;";
+
+        let mut interpreter = Interpreter::new();
+        let mut vm = VM::new();
+
+        assert!(interpreter.execute(resolve(program)).is_ok());
+        assert_eq!(vm.interpret(compile(program)), InterpretResult::InterpretOk);
+        assert_eq!(
+            format!("{:?}", interpreter.env.globals),
+            "[Integer(5), Integer(6)]"
+        );
+        assert_eq!(
+            format!("{:?}", interpreter.env.globals),
+            format!("{:?}", vm.globals)
+        );
+    }
+}
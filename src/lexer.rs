@@ -1,4 +1,11 @@
-use crate::error::{ErrorBag, LoxError};
+use std::{iter::Peekable, str::CharIndices};
+
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::{
+    error::{ErrorBag, LoxError},
+    intern::Symbol,
+};
 
 #[derive(Debug, Clone)]
 #[allow(unused)]
@@ -28,8 +35,10 @@ pub enum TokenKind {
     LessEqual,    /* Character '<=' */
 
     /* Literals */
-    Identifier(String),
+    Identifier(Symbol),
     QuotedString(String),
+    /// Part of a string that continues after a `${...}` expression
+    InterpolatedString(String),
     Integer(isize),
     Decimal(f64),
 
@@ -50,50 +59,152 @@ pub enum TokenKind {
     While,    /* Loop */
 
     /* Special */
-    Comment, /* Comments in the form // */
-    Eof,     /* End of file */
-    Invalid, /* Helper token to detect errors */
+    Comment,            /* Comments in the form // and nestable block comments */
+    DocComment(String), /* Comments in the form ///, kept for declarations */
+    Eof,                /* End of file */
+    Invalid,            /* Helper token to detect errors */
 }
 
+/// Lines and columns count from 1. `column` counts characters, while
+/// `utf16_column` counts UTF-16 code units as most editors do.
 #[derive(Debug, Clone)]
 pub struct Position {
     pub line: usize,
     pub column: usize,
+    pub utf16_column: usize,
+    /// Bytes from the start of the source
+    pub offset: usize,
+}
+
+impl Position {
+    /// Position known only by line and column, such as one read back from
+    /// a bytecode line table. It is assumed to be ASCII up to `column`
+    /// with an unknown offset.
+    pub fn new(line: usize, column: usize) -> Self {
+        Self {
+            line,
+            column,
+            utf16_column: column,
+            offset: 0,
+        }
+    }
+
+    /// Position right after `text`, when `text` starts at this position
+    pub fn advanced_by(&self, text: &str) -> Position {
+        let offset = self.offset + text.len();
+        match text.rfind('\n') {
+            Some(newline) => {
+                let line = &text[newline + 1..];
+                Position {
+                    line: self.line + text.matches('\n').count(),
+                    column: line.chars().count() + 1,
+                    utf16_column: line.encode_utf16().count() + 1,
+                    offset,
+                }
+            }
+            None => Position {
+                line: self.line,
+                column: self.column + text.chars().count(),
+                utf16_column: self.utf16_column + text.encode_utf16().count(),
+                offset,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenKind,
     pub span: Position,
+    /// Bytes of source the token covers, starting at `span.offset`
+    pub length: usize,
 }
 
+/// Scans borrowed source text in a single pass. `offset` only moves
+/// forward, so lexing is linear in the size of the input.
 pub struct Lexer<'a> {
-    input: Vec<char>,
-    cursor: usize,
+    source: &'a str,
+    /// Byte offset of the first character not consumed yet
+    offset: usize,
     span: Position,
+    /// Brace depth inside every `${` that is still open, innermost last
+    interpolations: Vec<usize>,
+    /// Set once the `Eof` token has been handed out
+    finished: bool,
     pub error_bag: &'a mut ErrorBag,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(file_contents: String, error_bag: &'a mut ErrorBag) -> Self {
+    pub fn new(source: &'a str, error_bag: &'a mut ErrorBag) -> Self {
+        Self::resume(source, Position::new(1, 1), error_bag)
+    }
+
+    /// Starts lexing at `start` instead of the beginning of `source`.
+    /// `start` must lie between two tokens and outside any `${`.
+    pub fn resume(source: &'a str, start: Position, error_bag: &'a mut ErrorBag) -> Self {
         Self {
-            input: file_contents.chars().collect(),
-            cursor: 0,
-            span: Position { line: 1, column: 1 },
+            source,
+            offset: start.offset,
+            span: start,
+            interpolations: Vec::new(),
+            finished: false,
             error_bag,
         }
     }
 
+    /// Position of the first character not consumed yet
+    pub fn position(&self) -> &Position {
+        &self.span
+    }
+
+    /// Whether the lexer is inside the expression of an open `${`
+    pub fn in_interpolation(&self) -> bool {
+        !self.interpolations.is_empty()
+    }
+
     pub fn next_token(&mut self) -> Option<Token> {
         self.skip_whitespace();
 
-        if self.input.is_empty() {
-            return None;
-        }
+        // Every token stream ends with exactly one `Eof`, placed after the
+        // trailing whitespace
+        let Some(current) = self.peek(0) else {
+            if self.finished {
+                return None;
+            }
+            self.finished = true;
+            if !self.interpolations.is_empty() {
+                self.interpolations.clear();
+                self.error_bag.errors.push(LoxError::LexerError(format!(
+                    "Unterminated string interpolation at line {}",
+                    self.span.line
+                )));
+            }
+            return Some(Token {
+                kind: TokenKind::Eof,
+                span: self.span.clone(),
+                length: 0,
+            });
+        };
 
-        let (tokenkind, length) = match self.input[0] {
-            '{' => (TokenKind::OpenBrace, 1),
-            '}' => (TokenKind::CloseBrace, 1),
+        // Token lengths are in bytes
+        let (tokenkind, length) = match current {
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                (TokenKind::OpenBrace, 1)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.read_quoted_string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    (TokenKind::CloseBrace, 1)
+                }
+                None => (TokenKind::CloseBrace, 1),
+            },
             '(' => (TokenKind::OpenParen, 1),
             ')' => (TokenKind::CloseParen, 1),
             ',' => (TokenKind::Comma, 1),
@@ -104,132 +215,193 @@ impl<'a> Lexer<'a> {
             '.' => (TokenKind::Dot, 1),
             '%' => (TokenKind::Percentage, 1),
             '=' => {
-                if self.peek() == '=' {
+                if self.peek(1) == Some('=') {
                     (TokenKind::Equal, 2)
                 } else {
                     (TokenKind::Assign, 1)
                 }
             }
             '!' => {
-                if self.peek() == '=' {
+                if self.peek(1) == Some('=') {
                     (TokenKind::NotEqual, 2)
                 } else {
                     (TokenKind::Bang, 1)
                 }
             }
             '>' => {
-                if self.peek() == '=' {
+                if self.peek(1) == Some('=') {
                     (TokenKind::GreaterEqual, 2)
                 } else {
                     (TokenKind::GreaterThan, 1)
                 }
             }
             '<' => {
-                if self.peek() == '=' {
+                if self.peek(1) == Some('=') {
                     (TokenKind::LessEqual, 2)
                 } else {
                     (TokenKind::LessThan, 1)
                 }
             }
-            '/' => {
-                if self.peek() == '/' {
-                    self.read_comment()
-                } else {
-                    (TokenKind::ForwardSlash, 1)
-                }
-            }
+            '/' => match self.peek(1) {
+                Some('/') => self.read_comment(),
+                Some('*') => self.read_block_comment(),
+                _ => (TokenKind::ForwardSlash, 1),
+            },
             '\"' => self.read_quoted_string(),
+            'r' if self.peek(1) == Some('"') => self.read_raw_string(),
             '0'..='9' => self.read_number(),
-            'a'..='z' | 'A'..='Z' | '_' => self.read_identifier(),
+            c if c == '_' || is_xid_start(c) => self.read_identifier(),
             _ => {
                 self.error_bag.errors.push(LoxError::LexerError(format!(
                     "Use of invalid token: \x1b[32m{}\x1b[0m at line {}, column {}",
-                    self.input[0], self.span.line, self.span.column
+                    current, self.span.line, self.span.column
                 )));
-                (TokenKind::Invalid, 1)
+                (TokenKind::Invalid, current.len_utf8())
             }
         };
 
-        self.chomp(length);
-        self.cursor = 0;
-        self.span.column += length;
+        let span = self.span.clone();
+        self.consume(length);
         Some(Token {
             kind: tokenkind,
-            span: Position {
-                line: self.span.line,
-                column: self.span.column - length,
-            },
+            span,
+            length,
         })
     }
 
-    fn advance_cursor(&mut self) {
-        if self.cursor < self.input.len() {
-            self.cursor += 1;
-        }
+    /// Moves past `length` bytes, counting lines and columns on the way
+    fn consume(&mut self, length: usize) {
+        self.span = self.position_at(length);
+        self.offset += length;
     }
 
-    fn skip_whitespace(&mut self) {
-        let mut index: usize = 0;
-        while (index < self.input.len()) && self.input[index].is_whitespace() {
-            self.span.column += 1;
-            if self.input[index] == '\n' {
-                self.span.line += 1;
-                self.span.column = 1;
-            }
-            index += 1;
-        }
+    /// Position of the character `index` bytes after the current offset
+    fn position_at(&self, index: usize) -> Position {
+        self.span.advanced_by(&self.rest()[..index])
+    }
 
-        self.input = self.input[index..].to_vec();
+    /// Source text not consumed yet
+    fn rest(&self) -> &'a str {
+        &self.source[self.offset..]
     }
 
-    fn peek(&self) -> char {
-        if self.cursor >= self.input.len() {
-            '\0'
-        } else {
-            self.input[self.cursor + 1]
-        }
+    /// Character `n` places after the current one, without consuming it
+    fn peek(&self, n: usize) -> Option<char> {
+        self.rest().chars().nth(n)
     }
 
-    fn chomp(&mut self, length: usize) {
-        self.input = self.input[length..].to_vec();
+    /// Byte length of the longest prefix of `text` matching `predicate`
+    fn scan(text: &str, predicate: impl Fn(char) -> bool) -> usize {
+        text.find(|c: char| !predicate(c)).unwrap_or(text.len())
     }
 
+    fn skip_whitespace(&mut self) {
+        let length = Self::scan(self.rest(), char::is_whitespace);
+        self.consume(length);
+    }
+
+    /// Decimal integers and decimals with an optional exponent, or integers
+    /// in hex (`0x`), binary (`0b`) and octal (`0o`). `_` may separate
+    /// digits. Malformed or overflowing literals become `Invalid` tokens.
     fn read_number(&mut self) -> (TokenKind, usize) {
-        while self.peek().is_numeric() {
-            self.advance_cursor();
-        }
+        let rest = self.rest();
+        let digits = |c: char| c.is_ascii_digit() || c == '_';
+
+        let radix = match rest.get(..2) {
+            Some("0x" | "0X") => 16,
+            Some("0b" | "0B") => 2,
+            Some("0o" | "0O") => 8,
+            _ => 10,
+        };
 
-        if self.peek() == '.' {
-            self.advance_cursor();
-            while self.peek().is_numeric() {
-                self.advance_cursor();
+        let mut length = if radix == 10 {
+            let mut length = Self::scan(rest, digits);
+            if rest[length..].starts_with('.') {
+                length += 1;
+                length += Self::scan(&rest[length..], digits);
             }
-
-            let number = self.input[0..self.cursor + 1].iter().collect::<String>();
-            (
-                TokenKind::Decimal(number.parse::<f64>().unwrap()),
-                self.cursor + 1,
-            )
+            if rest[length..].starts_with(['e', 'E']) {
+                length += 1;
+                if rest[length..].starts_with(['+', '-']) {
+                    length += 1;
+                }
+                length += Self::scan(&rest[length..], digits);
+            }
+            length
         } else {
-            let number = self.input[0..self.cursor + 1].iter().collect::<String>();
-            (
-                TokenKind::Integer(number.parse::<isize>().unwrap()),
-                self.cursor + 1,
-            )
+            2
+        };
+        // Letters glued to a number belong to the literal, so `0xFG` and
+        // `12ab` are reported whole
+        length += Self::scan(&rest[length..], is_xid_continue);
+
+        let literal = &rest[..length];
+        match Self::parse_number(literal, radix) {
+            Ok(kind) => (kind, length),
+            Err(reason) => {
+                self.error_bag.errors.push(LoxError::LexerError(format!(
+                    "Numeric literal \x1b[32m{literal}\x1b[0m {reason} at line {} column {}",
+                    self.span.line, self.span.column
+                )));
+                (TokenKind::Invalid, length)
+            }
         }
     }
 
-    fn read_identifier(&mut self) -> (TokenKind, usize) {
-        while self.cursor < self.input.len()
-            && (self.input[self.cursor].is_alphanumeric() || self.input[self.cursor] == '_')
+    fn parse_number(literal: &str, radix: u32) -> Result<TokenKind, String> {
+        let chars: Vec<char> = literal.chars().collect();
+        for (index, &c) in chars.iter().enumerate() {
+            let between_digits = index > 0
+                && chars[index - 1].is_digit(radix)
+                && chars
+                    .get(index + 1)
+                    .is_some_and(|next| next.is_digit(radix));
+            if c == '_' && !between_digits {
+                return Err("can only use _ between two digits".to_string());
+            }
+        }
+        let cleaned = literal.replace('_', "");
+
+        if radix != 10 {
+            let digits = &cleaned[2..];
+            if digits.is_empty() {
+                return Err("has no digits after its prefix".to_string());
+            }
+            if let Some(invalid) = digits.chars().find(|c| !c.is_digit(radix)) {
+                return Err(format!("has invalid digit '{invalid}' for base {radix}"));
+            }
+            return isize::from_str_radix(digits, radix)
+                .map(TokenKind::Integer)
+                .map_err(|_| "does not fit in an integer".to_string());
+        }
+
+        if let Some(invalid) = cleaned
+            .chars()
+            .find(|&c| !(c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-')))
         {
-            self.cursor += 1;
+            return Err(format!("has invalid character '{invalid}'"));
+        }
+
+        if cleaned.contains(['.', 'e', 'E']) {
+            match cleaned.parse::<f64>() {
+                Ok(decimal) if decimal.is_finite() => Ok(TokenKind::Decimal(decimal)),
+                Ok(_) => Err("does not fit in a decimal".to_string()),
+                Err(_) => Err("is not a valid decimal".to_string()),
+            }
+        } else {
+            cleaned
+                .parse::<isize>()
+                .map(TokenKind::Integer)
+                .map_err(|_| "does not fit in an integer".to_string())
         }
+    }
 
-        let token = self.input[0..self.cursor].iter().collect::<String>();
-        let token_len = token.len();
+    fn read_identifier(&mut self) -> (TokenKind, usize) {
+        let rest = self.rest();
+        let length = Self::scan(rest, is_xid_continue);
+        let token = &rest[..length];
 
-        let kind = match token.as_str() {
+        let kind = match token {
             "fn" => TokenKind::Function,
             "struct" => TokenKind::Struct,
             "let" => TokenKind::Let,
@@ -244,35 +416,166 @@ impl<'a> Lexer<'a> {
             "or" => TokenKind::Or,
             "and" => TokenKind::And,
             "print" => TokenKind::Print,
-            _ => TokenKind::Identifier(token),
+            _ => TokenKind::Identifier(Symbol::intern(token)),
         };
 
-        (kind, token_len)
+        (kind, length)
     }
 
+    /// Strings may span lines and support the escapes `\n`, `\t`, `\r`,
+    /// `\0`, `\"`, `\'`, `\\`, `\$` and `\u{...}` with up to six hex
+    /// digits. `${` ends the token as an `InterpolatedString`, and the
+    /// string resumes at the `}` that closes the embedded expression.
     fn read_quoted_string(&mut self) -> (TokenKind, usize) {
-        while self.cursor < self.input.len() - 1 && self.peek() != '\"' {
-            self.advance_cursor();
+        let rest = self.rest();
+        let mut chars = rest.char_indices().peekable();
+        chars.next(); // Opening quote or closing brace
+        let mut qstring = String::new();
+        let mut valid = true;
+
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    let kind = match valid {
+                        true => TokenKind::QuotedString(qstring),
+                        false => TokenKind::Invalid,
+                    };
+                    return (kind, index + 1);
+                }
+                '$' if chars.next_if(|&(_, c)| c == '{').is_some() => {
+                    self.interpolations.push(0);
+                    let kind = match valid {
+                        true => TokenKind::InterpolatedString(qstring),
+                        false => TokenKind::Invalid,
+                    };
+                    return (kind, index + 2);
+                }
+                '\\' => match Self::read_escape(&mut chars) {
+                    Ok(escaped) => qstring.push(escaped),
+                    Err(message) => {
+                        let position = self.position_at(index);
+                        self.error_bag.errors.push(LoxError::LexerError(format!(
+                            "{message} at line {} column {}",
+                            position.line, position.column
+                        )));
+                        valid = false;
+                    }
+                },
+                _ => qstring.push(c),
+            }
         }
 
-        if self.cursor == self.input.len() - 1 {
-            self.error_bag.errors.push(LoxError::LexerError(format!(
-                "Unterminated string at line {}",
-                self.span.line
-            )));
-            return (TokenKind::Invalid, self.cursor + 1);
+        self.unterminated_string(rest.len())
+    }
+
+    fn read_escape(chars: &mut Peekable<CharIndices>) -> Result<char, String> {
+        let escaped = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'r')) => '\r',
+            Some((_, '0')) => '\0',
+            Some((_, '"')) => '"',
+            Some((_, '\'')) => '\'',
+            Some((_, '$')) => '$',
+            Some((_, '\\')) => '\\',
+            Some((_, 'u')) => {
+                if chars.next_if(|&(_, c)| c == '{').is_none() {
+                    return Err("Expected \x1b[32m{\x1b[0m after unicode escape \\u".to_string());
+                }
+
+                let mut digits = String::new();
+                while let Some((_, digit)) = chars.next_if(|&(_, c)| c.is_ascii_hexdigit()) {
+                    digits.push(digit);
+                }
+                if chars.next_if(|&(_, c)| c == '}').is_none()
+                    || digits.is_empty()
+                    || digits.len() > 6
+                {
+                    return Err(format!(
+                        "Unicode escape must be 1 to 6 hex digits in braces, got \x1b[32m\\u{{{digits}\x1b[0m"
+                    ));
+                }
+
+                let code = u32::from_str_radix(&digits, 16).unwrap();
+                return char::from_u32(code).ok_or(format!(
+                    "Unicode escape \x1b[32m\\u{{{digits}}}\x1b[0m is not a valid character"
+                ));
+            }
+            Some((_, other)) => {
+                return Err(format!(
+                    "Invalid escape sequence \x1b[32m\\{}\x1b[0m",
+                    other.escape_debug()
+                ))
+            }
+            None => return Err("Unterminated escape sequence".to_string()),
+        };
+
+        Ok(escaped)
+    }
+
+    /// `r"..."` keeps backslashes as they are written
+    fn read_raw_string(&mut self) -> (TokenKind, usize) {
+        let rest = self.rest();
+
+        match rest[2..].find('"') {
+            Some(end) => (
+                TokenKind::QuotedString(rest[2..end + 2].to_string()),
+                end + 3,
+            ),
+            None => self.unterminated_string(rest.len()),
         }
+    }
 
-        let qstring = self.input[1..self.cursor + 1].iter().collect::<String>();
-        (TokenKind::QuotedString(qstring), self.cursor + 2)
+    fn unterminated_string(&mut self, length: usize) -> (TokenKind, usize) {
+        self.error_bag.errors.push(LoxError::LexerError(format!(
+            "Unterminated string at line {}",
+            self.span.line
+        )));
+        (TokenKind::Invalid, length)
     }
 
+    /// The newline ending the comment is left for `skip_whitespace`, so the
+    /// next line is counted. Exactly three slashes make a doc comment.
     fn read_comment(&mut self) -> (TokenKind, usize) {
-        while self.cursor < self.input.len() && self.input[self.cursor] != '\n' {
-            self.advance_cursor();
+        let rest = self.rest();
+        let length = Self::scan(rest, |c| c != '\n');
+
+        if rest.starts_with("///") && !rest.starts_with("////") {
+            let text = rest[3..length]
+                .strip_prefix(' ')
+                .unwrap_or(&rest[3..length]);
+            return (TokenKind::DocComment(text.trim_end().to_string()), length);
         }
+        (TokenKind::Comment, length)
+    }
 
-        (TokenKind::Comment, self.cursor + 1)
+    /// `/* ... */` comments nest, so commenting out code that already
+    /// contains one keeps working
+    fn read_block_comment(&mut self) -> (TokenKind, usize) {
+        let rest = self.rest();
+        let mut depth = 0;
+        let mut index = 0;
+
+        while let Some(c) = rest[index..].chars().next() {
+            if rest[index..].starts_with("/*") {
+                depth += 1;
+                index += 2;
+            } else if rest[index..].starts_with("*/") {
+                depth -= 1;
+                index += 2;
+                if depth == 0 {
+                    return (TokenKind::Comment, index);
+                }
+            } else {
+                index += c.len_utf8();
+            }
+        }
+
+        self.error_bag.errors.push(LoxError::LexerError(format!(
+            "Unterminated block comment starting at line {} column {}",
+            self.span.line, self.span.column
+        )));
+        (TokenKind::Invalid, rest.len())
     }
 }
 
@@ -330,23 +633,245 @@ impl std::fmt::Display for TokenKind {
 mod tests {
 
     use super::{Lexer, TokenKind};
-    use crate::error::ErrorBag;
+    use crate::{
+        error::{ErrorBag, LoxError},
+        test_support::Xorshift,
+    };
 
     #[test]
     fn lexer_recognizes_invalid_tokens() {
         let invalid_program = "let x = &y".to_string(); // & not a valid token
         let mut error_bag = ErrorBag { errors: vec![] };
 
-        let lexer = Lexer::new(invalid_program, &mut error_bag);
+        let lexer = Lexer::new(&invalid_program, &mut error_bag);
 
         let _tokens: Vec<_> = lexer
             .into_iter()
-            .filter(|token| match token.kind {
-                TokenKind::Comment | TokenKind::Invalid => false,
-                _ => true,
-            })
+            .filter(|token| !matches!(token.kind, TokenKind::Comment | TokenKind::Invalid))
             .collect();
 
         assert!(!error_bag.errors.is_empty());
     }
+
+    #[test]
+    fn lexer_tracks_positions_across_lines() {
+        let program = "let s = \"é\"; // comment\n  print s;";
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let spans: Vec<_> = Lexer::new(program, &mut error_bag)
+            .map(|token| (token.span.line, token.span.column))
+            .collect();
+
+        assert!(error_bag.errors.is_empty());
+        assert_eq!(
+            spans,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 12),
+                (1, 14),
+                (2, 3),
+                (2, 9),
+                (2, 10),
+                (2, 11)
+            ]
+        );
+    }
+
+    #[test]
+    fn lexer_processes_string_escapes() {
+        let program = "print \"a\\tb\\n\\\"\\u{1F600}\\\\\";\nprint \"two\nlines\" r\"C:\\dir\\n\";\nprint \"\\q\";";
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+        let strings: Vec<_> = tokens
+            .iter()
+            .filter_map(|token| match token.kind {
+                TokenKind::QuotedString(ref s) => Some((s.as_str(), token.span.line)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            strings,
+            vec![
+                ("a\tb\n\"\u{1F600}\\", 1),
+                ("two\nlines", 2),
+                ("C:\\dir\\n", 3)
+            ]
+        );
+        // Lines inside the multi-line string are counted
+        assert_eq!(tokens.last().unwrap().span.line, 4);
+        assert!(matches!(
+            error_bag.errors.as_slice(),
+            [LoxError::LexerError(message)] if message.ends_with("at line 4 column 8")
+        ));
+    }
+
+    #[test]
+    fn lexer_splits_interpolated_strings() {
+        let program = "\"a ${ {x} } b ${ \"c ${y}\" } d\"";
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let kinds: Vec<_> = Lexer::new(program, &mut error_bag)
+            .map(|token| match token.kind {
+                TokenKind::InterpolatedString(s) => format!("<{s}"),
+                TokenKind::QuotedString(s) => format!("{s}>"),
+                TokenKind::Identifier(name) => name.to_string(),
+                TokenKind::OpenBrace => "{".to_string(),
+                TokenKind::CloseBrace => "}".to_string(),
+                other => format!("{other:?}"),
+            })
+            .collect();
+
+        assert!(error_bag.errors.is_empty());
+        assert_eq!(
+            kinds,
+            vec!["<a ", "{", "x", "}", "< b ", "<c ", "y", ">", " d>", "Eof"]
+        );
+    }
+
+    #[test]
+    fn lexer_reads_extended_numeric_literals() {
+        let program = "0xFF 0b1010 0o17 1_000_000 1.5e-3 2E3 99999999999999999999 0xFG 1__0";
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+        let values: Vec<_> = tokens
+            .iter()
+            .map(|token| match token.kind {
+                TokenKind::Integer(i) => Some(i as f64),
+                TokenKind::Decimal(d) => Some(d),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                Some(255.0),
+                Some(10.0),
+                Some(15.0),
+                Some(1_000_000.0),
+                Some(0.0015),
+                Some(2000.0),
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+        assert_eq!(error_bag.errors.len(), 3);
+    }
+
+    #[test]
+    fn lexer_nests_block_comments() {
+        let program = "/* a /* b */ c */ let /// doc\n/* open /* */";
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+        assert!(matches!(
+            tokens.iter().map(|token| &token.kind).collect::<Vec<_>>().as_slice(),
+            [
+                TokenKind::Comment,
+                TokenKind::Let,
+                TokenKind::DocComment(doc),
+                TokenKind::Invalid,
+                TokenKind::Eof
+            ] if doc == "doc"
+        ));
+        assert!(matches!(
+            error_bag.errors.as_slice(),
+            [LoxError::LexerError(message)] if message.ends_with("at line 2 column 1")
+        ));
+    }
+
+    #[test]
+    fn lexer_reads_unicode_identifiers() {
+        let program = "let 😀 = 1; let café_2 = \"😀\"; let 名前 = café_2;";
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+        // The emoji is not an identifier character
+        assert_eq!(error_bag.errors.len(), 1);
+        let identifiers: Vec<_> = tokens
+            .iter()
+            .filter_map(|token| match token.kind {
+                TokenKind::Identifier(name) => Some((
+                    name.to_string(),
+                    token.span.column,
+                    token.span.utf16_column,
+                    token.span.offset,
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            identifiers,
+            vec![
+                ("café_2".to_string(), 16, 17, 18),
+                ("名前".to_string(), 34, 36, 40),
+                ("café_2".to_string(), 39, 41, 49),
+            ]
+        );
+    }
+
+    /// Checks the invariants every token stream keeps, whatever the input
+    fn assert_well_formed(program: &str) {
+        let mut error_bag = ErrorBag { errors: vec![] };
+
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+        let (eof, tokens) = tokens.split_last().expect("no Eof token");
+        assert!(matches!(eof.kind, TokenKind::Eof), "{program:?}");
+        assert_eq!(eof.span.offset, program.len(), "{program:?}");
+        let mut end = 0;
+        for token in tokens {
+            assert!(!matches!(token.kind, TokenKind::Eof), "{program:?}");
+            assert!(token.length > 0 && token.span.offset >= end, "{program:?}");
+            end = token.span.offset + token.length;
+            assert!(program.is_char_boundary(end), "{program:?}");
+        }
+        assert!(end <= program.len(), "{program:?}");
+    }
+
+    #[test]
+    fn lexer_handles_input_ending_mid_token() {
+        let endings = [
+            "", "=", "!", "<", ">", "/", "\"", "r", "r\"", "\"\\", "\"\\u{", "\"\\u{1F", "\"$",
+            "\"${", "\"${\"", "\"${x}", "0x", "0b_", "1.", "1e", "1e+", "/*", "/* /*", "///", "é",
+            "😀", "\u{0}",
+        ];
+
+        for ending in endings {
+            assert_well_formed(ending);
+            assert_well_formed(&format!("let x = {ending}"));
+        }
+    }
+
+    #[test]
+    fn lexer_never_panics_on_arbitrary_input() {
+        let alphabet: Vec<char> =
+            "=!<>/*+-%;.,(){}\"\\$r_xeEb0o19. \t\n\rlétprint😀\u{0}\u{301}\u{FEFF}"
+                .chars()
+                .collect();
+
+        let mut rng = Xorshift::new(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..2000 {
+            let length = rng.below(40);
+            let program: String = (0..length)
+                .map(|_| match rng.below(8) {
+                    // Any Unicode scalar value now and then
+                    0 => char::from_u32(rng.below(0x11_0000) as u32).unwrap_or('\u{FFFD}'),
+                    _ => alphabet[rng.below(alphabet.len())],
+                })
+                .collect();
+            assert_well_formed(&program);
+        }
+    }
 }
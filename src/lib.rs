@@ -0,0 +1,22 @@
+pub mod ast;
+pub mod bytecode;
+pub mod cancel;
+pub mod compiler;
+pub mod cst;
+pub mod disassembler;
+pub mod error;
+pub mod gc;
+pub mod incremental;
+pub mod intern;
+pub mod interpreter;
+pub mod lexer;
+pub mod lint;
+pub mod loxc;
+pub mod optimizer;
+pub mod parser;
+pub mod resolver;
+#[cfg(test)]
+mod test_support;
+pub mod value;
+pub mod verifier;
+pub mod vm;
@@ -0,0 +1,340 @@
+use crate::{
+    ast::{Expression, LiteralKind, Statement, Variable},
+    error::{ErrorBag, LoxError},
+    intern::Symbol,
+    interpreter::{Environment, Eval},
+    lexer::{Lexer, Position, TokenKind},
+};
+
+/// Comment directives that set the level of a lint for the whole file,
+/// e.g. `// lox-allow: unused-variable` or `/* lox-deny: unused-variable */`
+const DIRECTIVES: [(&str, Level); 2] = [("lox-allow:", Level::Allow), ("lox-deny:", Level::Deny)];
+
+/// Lints that apply to the current grammar. It has no blocks, control flow
+/// or return, so there is nothing for unreachable-code or shadowing lints
+/// to report yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lint {
+    UnusedVariable,
+    ConstantComparison,
+    UndeclaredAssignment,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 3] = [
+        Lint::UnusedVariable,
+        Lint::ConstantComparison,
+        Lint::UndeclaredAssignment,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UnusedVariable => "unused-variable",
+            Self::ConstantComparison => "constant-comparison",
+            Self::UndeclaredAssignment => "undeclared-assignment",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+/// What a lint does when it fires. Every lint warns unless told otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    /// Reported as a `LoxError::LintError`, which fails the run
+    Deny,
+}
+
+struct Binding {
+    name: Symbol,
+    span: Position,
+    read: bool,
+}
+
+/// Reports suspicious but valid code as `LoxError::Warning`s. Runs on a
+/// resolved program and never executes it.
+pub struct Linter<'a> {
+    /// Later entries override earlier ones for the same lint
+    levels: Vec<(Lint, Level)>,
+    bindings: Vec<Binding>,
+    implicit_globals: Vec<Symbol>,
+    pub error_bag: &'a mut ErrorBag,
+}
+
+impl<'a> Linter<'a> {
+    pub fn new(error_bag: &'a mut ErrorBag) -> Self {
+        Self {
+            levels: Vec::new(),
+            bindings: Vec::new(),
+            implicit_globals: Vec::new(),
+            error_bag,
+        }
+    }
+
+    pub fn set_level(&mut self, lint: Lint, level: Level) {
+        self.levels.push((lint, level));
+    }
+
+    pub fn allow(&mut self, lint: Lint) {
+        self.set_level(lint, Level::Allow);
+    }
+
+    pub fn deny(&mut self, lint: Lint) {
+        self.set_level(lint, Level::Deny);
+    }
+
+    fn level(&self, lint: Lint) -> Level {
+        self.levels
+            .iter()
+            .rev()
+            .find(|&&(set, _)| set == lint)
+            .map_or(Level::Warn, |&(_, level)| level)
+    }
+
+    /// Picks up `lox-allow: <lint>, <lint>` and `lox-deny: <lint>`
+    /// directives from the comments of the source. Lexer errors were
+    /// reported when the program was lexed for parsing, so they are dropped
+    /// here.
+    pub fn levels_from_comments(&mut self, source: &str) {
+        let mut lexer_errors = ErrorBag { errors: vec![] };
+        for token in Lexer::new(source, &mut lexer_errors) {
+            if !matches!(token.kind, TokenKind::Comment) {
+                continue;
+            }
+            let comment = &source[token.span.offset..token.span.offset + token.length];
+            let comment = comment.strip_suffix("*/").unwrap_or(comment);
+            for (directive, level) in DIRECTIVES {
+                let Some((_, names)) = comment.split_once(directive) else {
+                    continue;
+                };
+
+                for name in names.split(',') {
+                    if let Some(lint) = Lint::from_name(name.trim()) {
+                        self.set_level(lint, level);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn lint(&mut self, statements: &[Box<Statement>]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+
+        let unused: Vec<String> = self
+            .bindings
+            .iter()
+            .filter(|binding| !binding.read)
+            .map(|binding| {
+                format!(
+                    "Variable \x1b[32m{}\x1b[0m is never read at line {} column {}",
+                    binding.name, binding.span.line, binding.span.column
+                )
+            })
+            .collect();
+
+        for message in unused {
+            self.warn(Lint::UnusedVariable, message);
+        }
+    }
+
+    fn warn(&mut self, lint: Lint, message: String) {
+        match self.level(lint) {
+            Level::Allow => {}
+            Level::Warn => self.error_bag.errors.push(LoxError::Warning(lint, message)),
+            Level::Deny => self
+                .error_bag
+                .errors
+                .push(LoxError::LintError(lint, message)),
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Print(expr, _) | Statement::Expr(expr) => self.expression(expr),
+            Statement::Let(variable, initializer, _) => {
+                self.expression(initializer);
+                self.bindings.push(Binding {
+                    name: variable.name,
+                    span: variable.span.clone(),
+                    read: false,
+                });
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Binary(binary) => {
+                self.expression(&binary.lhs);
+                self.expression(&binary.rhs);
+                self.constant_comparison(expr);
+            }
+            Expression::Unary(unary) => self.expression(&unary.rhs),
+            Expression::Grouping(inner) => self.expression(inner),
+            Expression::Literal(..) => {}
+            Expression::Variable(variable) => {
+                if let Some(binding) = self.binding(variable) {
+                    binding.read = true;
+                }
+            }
+            Expression::Assign(variable, value) => {
+                self.expression(value);
+                self.assignment(variable);
+            }
+            Expression::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part);
+                }
+            }
+        }
+    }
+
+    fn binding(&mut self, variable: &Variable) -> Option<&mut Binding> {
+        self.bindings
+            .iter_mut()
+            .rev()
+            .find(|binding| binding.name == variable.name)
+    }
+
+    fn assignment(&mut self, variable: &Variable) {
+        if self.binding(variable).is_some() || self.implicit_globals.contains(&variable.name) {
+            return;
+        }
+
+        self.implicit_globals.push(variable.name);
+        self.warn(
+            Lint::UndeclaredAssignment,
+            format!(
+                "Assignment to undeclared variable \x1b[32m{}\x1b[0m creates a global at line {} column {}",
+                variable.name, variable.span.line, variable.span.column
+            ),
+        );
+    }
+
+    fn constant_comparison(&mut self, expr: &Expression) {
+        let Expression::Binary(binary) = expr else {
+            return;
+        };
+
+        let is_equality = matches!(binary.operator.kind, TokenKind::Equal | TokenKind::NotEqual);
+        let is_ordering = matches!(
+            binary.operator.kind,
+            TokenKind::GreaterThan
+                | TokenKind::GreaterEqual
+                | TokenKind::LessThan
+                | TokenKind::LessEqual
+        );
+        if !is_equality && !is_ordering {
+            return;
+        }
+
+        let (Some(lhs), Some(rhs)) = (constant(&binary.lhs), constant(&binary.rhs)) else {
+            return;
+        };
+
+        // Only fold comparisons the interpreter accepts, others are runtime errors
+        let comparable = match (lhs, rhs) {
+            (
+                LiteralKind::Integer(_) | LiteralKind::Decimal(_),
+                LiteralKind::Integer(_) | LiteralKind::Decimal(_),
+            ) => true,
+            (LiteralKind::Boolean(_), LiteralKind::Boolean(_)) => true,
+            (LiteralKind::None, _) | (_, LiteralKind::None) => is_equality,
+            _ => false,
+        };
+        if !comparable {
+            return;
+        }
+
+        let Ok(result) = expr.eval(&mut Environment::default()) else {
+            return;
+        };
+        self.warn(
+            Lint::ConstantComparison,
+            format!(
+                "Comparison between constants is always \x1b[32m{}\x1b[0m at line {} column {}",
+                result, binary.operator.span.line, binary.operator.span.column
+            ),
+        );
+    }
+}
+
+fn constant(expr: &Expression) -> Option<&LiteralKind> {
+    match expr {
+        Expression::Literal(literal, _) => Some(literal),
+        Expression::Grouping(inner) => constant(inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Level, Lint, Linter};
+    use crate::{
+        error::{ErrorBag, LoxError},
+        test_support::resolve_into,
+    };
+
+    fn lint(program: &str) -> Vec<Lint> {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let ast = resolve_into(program, &mut error_bag);
+
+        let mut linter = Linter::new(&mut error_bag);
+        linter.levels_from_comments(program);
+        linter.lint(&ast);
+
+        error_bag
+            .errors
+            .iter()
+            .filter_map(|e| match e {
+                LoxError::Warning(lint, _) => Some(*lint),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn linter_reports_warnings() {
+        assert_eq!(lint("let x = 1; print 2;"), vec![Lint::UnusedVariable]);
+        assert_eq!(lint("print 1 < (2);"), vec![Lint::ConstantComparison]);
+        assert_eq!(lint("y = 1; y = 2;"), vec![Lint::UndeclaredAssignment]);
+        assert!(lint("let x = 1; print x == 1;").is_empty());
+    }
+
+    #[test]
+    fn linter_honours_allow_comments() {
+        assert!(lint("// lox-allow: unused-variable\nlet x = 1;").is_empty());
+        assert!(lint("/* lox-allow: unused-variable */ let x = 1;").is_empty());
+        assert_eq!(
+            lint("print \"// lox-allow: unused-variable\"; let x = 1;"),
+            vec![Lint::UnusedVariable]
+        );
+    }
+
+    #[test]
+    fn linter_reports_denied_lints_as_errors() {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let program = "// lox-deny: unused-variable, constant-comparison\nlet x = 1; print 1 < 2;";
+        let ast = resolve_into(program, &mut error_bag);
+
+        let mut linter = Linter::new(&mut error_bag);
+        linter.levels_from_comments(program);
+        linter.set_level(Lint::ConstantComparison, Level::Warn);
+        linter.lint(&ast);
+
+        assert!(matches!(
+            error_bag.errors[..],
+            [
+                LoxError::Warning(Lint::ConstantComparison, _),
+                LoxError::LintError(Lint::UnusedVariable, _)
+            ]
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! Binary `.loxc` format for compiled chunks. All integers are little endian.
+//!
+//! ```text
+//! magic       b"LOXC"
+//! version     u16
+//! chunk       constants   u32 count, then per entry a u8 tag and its payload
+//!             code        u32 length, then the raw bytes
+//!             lines       u32 count, then per run u32 line, u32 column, u32 length
+//! ```
+//!
+//! Function constants carry their name, arity, upvalue sources and a nested
+//! chunk in the same layout.
+
+use std::rc::Rc;
+
+use crate::{
+    bytecode::{Chunk, LineRun},
+    error::LoxError,
+    intern::Symbol,
+    value::{Prototype, UpvalueSource, Value},
+};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Version 2 added function constants and made `OpReturn` pop its result
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Deepest function nesting accepted when reading
+const MAX_NESTING: usize = 64;
+
+const TAG_INTEGER: u8 = 0;
+const TAG_DECIMAL: u8 = 1;
+const TAG_BOOLEAN: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_NONE: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+pub fn write_chunk(chunk: &Chunk) -> Result<Vec<u8>, LoxError> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    write_body(&mut bytes, chunk)?;
+    Ok(bytes)
+}
+
+fn write_body(bytes: &mut Vec<u8>, chunk: &Chunk) -> Result<(), LoxError> {
+    write_len(bytes, chunk.constant_pool.len())?;
+    for constant in &chunk.constant_pool {
+        match *constant {
+            Value::Integer(i) => {
+                bytes.push(TAG_INTEGER);
+                bytes.extend_from_slice(&(i as i64).to_le_bytes());
+            }
+            Value::Decimal(d) => {
+                bytes.push(TAG_DECIMAL);
+                bytes.extend_from_slice(&d.to_le_bytes());
+            }
+            Value::Boolean(b) => {
+                bytes.push(TAG_BOOLEAN);
+                bytes.push(b as u8);
+            }
+            Value::String(ref s) => {
+                bytes.push(TAG_STRING);
+                write_str(bytes, s)?;
+            }
+            Value::None => bytes.push(TAG_NONE),
+            Value::Prototype(ref prototype) => {
+                bytes.push(TAG_FUNCTION);
+                write_str(bytes, prototype.name.as_str())?;
+                write_len(bytes, prototype.arity)?;
+                write_len(bytes, prototype.upvalues.len())?;
+                for source in &prototype.upvalues {
+                    bytes.push(source.is_local as u8);
+                    bytes.push(source.index);
+                }
+                write_body(bytes, &prototype.chunk)?;
+            }
+            ref other => {
+                return Err(LoxError::LoadError(format!(
+                    "Cannot serialize constant \x1b[34m{other}\x1b[0m"
+                )))
+            }
+        }
+    }
+
+    write_len(bytes, chunk.code.len())?;
+    bytes.extend_from_slice(&chunk.code);
+
+    write_len(bytes, chunk.lines.len())?;
+    for run in &chunk.lines {
+        write_len(bytes, run.line)?;
+        write_len(bytes, run.column)?;
+        write_len(bytes, run.length)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_chunk(bytes: &[u8]) -> Result<Chunk, LoxError> {
+    let mut reader = Reader { bytes, cursor: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(corrupt("not a .loxc file"));
+    }
+
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != FORMAT_VERSION {
+        return Err(LoxError::LoadError(format!(
+            "Unsupported .loxc format version \x1b[32m{version}\x1b[0m, expected {FORMAT_VERSION}"
+        )));
+    }
+
+    let chunk = read_body(&mut reader, 0)?;
+
+    if reader.cursor != bytes.len() {
+        return Err(corrupt("trailing bytes after chunk"));
+    }
+
+    Ok(chunk)
+}
+
+fn read_body(reader: &mut Reader, nesting: usize) -> Result<Chunk, LoxError> {
+    if nesting > MAX_NESTING {
+        return Err(corrupt("functions nested too deeply"));
+    }
+
+    let mut chunk = Chunk::new();
+
+    for _ in 0..reader.len()? {
+        let constant = match reader.byte()? {
+            TAG_INTEGER => {
+                let i = i64::from_le_bytes(reader.array()?);
+                Value::Integer(isize::try_from(i).map_err(|_| corrupt("integer out of range"))?)
+            }
+            TAG_DECIMAL => Value::Decimal(f64::from_le_bytes(reader.array()?)),
+            TAG_BOOLEAN => Value::Boolean(reader.flag()?),
+            TAG_STRING => Value::String(reader.str()?.into()),
+            TAG_NONE => Value::None,
+            TAG_FUNCTION => {
+                let name = Symbol::intern(reader.str()?);
+                let arity = reader.len()?;
+
+                let mut upvalues = Vec::new();
+                for _ in 0..reader.len()? {
+                    upvalues.push(UpvalueSource {
+                        is_local: reader.flag()?,
+                        index: reader.byte()?,
+                    });
+                }
+
+                Value::Prototype(Rc::new(Prototype {
+                    name,
+                    arity,
+                    upvalues,
+                    chunk: read_body(reader, nesting + 1)?,
+                }))
+            }
+            tag => return Err(corrupt(&format!("unknown constant tag {tag}"))),
+        };
+        chunk.constant_pool.push(constant);
+    }
+
+    let code_len = reader.len()?;
+    chunk.code = reader.take(code_len)?.to_vec();
+
+    for _ in 0..reader.len()? {
+        chunk.lines.push(LineRun {
+            line: reader.len()?,
+            column: reader.len()?,
+            length: reader.len()?,
+        });
+    }
+
+    if chunk.lines.iter().map(|run| run.length).sum::<usize>() != chunk.code.len() {
+        return Err(corrupt("line table does not cover the code"));
+    }
+
+    Ok(chunk)
+}
+
+fn corrupt(reason: &str) -> LoxError {
+    LoxError::LoadError(format!("Corrupt .loxc file: {reason}"))
+}
+
+fn write_len(bytes: &mut Vec<u8>, len: usize) -> Result<(), LoxError> {
+    let len = u32::try_from(len)
+        .map_err(|_| LoxError::LoadError("Chunk too large to serialize".to_string()))?;
+    bytes.extend_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) -> Result<(), LoxError> {
+    write_len(bytes, s.len())?;
+    bytes.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoxError> {
+        let end = self
+            .cursor
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| corrupt("unexpected end of file"))?;
+
+        let slice = &self.bytes[self.cursor..end];
+        self.cursor = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoxError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn byte(&mut self) -> Result<u8, LoxError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool, LoxError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(corrupt("invalid boolean")),
+        }
+    }
+
+    fn len(&mut self) -> Result<usize, LoxError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str, LoxError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| corrupt("string is not valid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{read_chunk, write_chunk};
+    use crate::test_support::compile;
+
+    #[test]
+    fn loxc_round_trips_and_rejects_corrupt_files() {
+        let chunk = compile("let x = 2.5; print \"total\"; print x * 4 == None;");
+
+        let bytes = write_chunk(&chunk).unwrap();
+        let loaded = read_chunk(&bytes).unwrap();
+
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.lines, chunk.lines);
+        assert_eq!(
+            format!("{:?}", loaded.constant_pool),
+            format!("{:?}", chunk.constant_pool)
+        );
+
+        assert!(read_chunk(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_chunk(b"NOPE").is_err());
+
+        let mut future = bytes.clone();
+        future[4] = 99;
+        assert!(read_chunk(&future).is_err());
+    }
+}
@@ -1,40 +1,220 @@
-mod ast;
-mod bytecode;
-mod error;
-mod interpreter;
-mod lexer;
-mod parser;
-mod vm;
-use interpreter::Interpreter;
-use lexer::Lexer;
-
-use crate::{error::ErrorBag, lexer::TokenKind, parser::Parser};
+use std::time::Duration;
+
+use lox::{
+    bytecode::Chunk,
+    cancel::CancellationToken,
+    compiler::Compiler,
+    disassembler::disassemble_chunk,
+    error::{self, ErrorBag, LoxError},
+    interpreter::Interpreter,
+    lexer::{Lexer, TokenKind},
+    lint::{Level, Lint, Linter},
+    loxc::{read_chunk, write_chunk},
+    optimizer::{fold_constants, peephole},
+    parser::Parser,
+    resolver::Resolver,
+    vm::{InterpretResult, VM},
+};
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
 
+    let mut path: Option<&String> = None;
+    let mut timeout: Option<Duration> = None;
+    let mut lint = false;
+    let mut use_vm = false;
+    let mut optimize = true;
+    let mut options = RunOptions::default();
+    let mut emit_loxc: Option<&String> = None;
+    let mut lint_levels: Vec<(Lint, Level)> = Vec::new();
+
+    let mut args_iter = args.iter().skip(1);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let millis = args_iter.next().and_then(|ms| ms.parse::<u64>().ok());
+                match millis {
+                    Some(ms) => timeout = Some(Duration::from_millis(ms)),
+                    None => {
+                        eprintln!("--timeout expects a number of milliseconds");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--lint" => lint = true,
+            "--vm" => use_vm = true,
+            "-O0" => optimize = false,
+            "-O1" => optimize = true,
+            "--disassemble" => options.disassemble = true,
+            "--trace" => options.trace = true,
+            "--gc-stress" => options.gc_stress = true,
+            "--gc-stats" => options.gc_stats = true,
+            "--emit-loxc" => match args_iter.next() {
+                Some(output) => emit_loxc = Some(output),
+                None => {
+                    eprintln!("--emit-loxc expects an output path");
+                    std::process::exit(1);
+                }
+            },
+            flag @ ("--allow" | "--deny") => {
+                let level = match flag {
+                    "--allow" => Level::Allow,
+                    _ => Level::Deny,
+                };
+                match args_iter.next().and_then(|name| Lint::from_name(name)) {
+                    Some(lint) => lint_levels.push((lint, level)),
+                    None => {
+                        let names: Vec<_> = Lint::ALL.iter().map(|l| l.name()).collect();
+                        eprintln!("{flag} expects one of: {}", names.join(", "));
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => path = Some(arg),
+        }
+    }
+
+    let Some(path) = path else {
+        eprintln!("Usage: lox [--vm] [-O0 | -O1] [--trace] [--disassemble] [--gc-stress] [--gc-stats] [--timeout <ms>] [--lint [--allow <lint> | --deny <lint>]...] [--emit-loxc <out.loxc>] <script | script.loxc>");
+        std::process::exit(1);
+    };
+
+    let cancel = match timeout {
+        Some(timeout) => CancellationToken::with_timeout(timeout),
+        None => CancellationToken::new(),
+    };
+
+    if path.ends_with(".loxc") {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error::die(LoxError::LoadError(format!(
+                    "Cannot read \x1b[32m{path}\x1b[0m: {e}"
+                )));
+                return;
+            }
+        };
+        match read_chunk(&bytes) {
+            Ok(chunk) => run_chunk(chunk, path, &options, cancel),
+            Err(e) => error::die(e),
+        }
+        return;
+    }
+
     let mut error_bag = ErrorBag { errors: vec![] };
 
-    let lexer = Lexer::new(std::fs::read_to_string(&args[1]).unwrap(), &mut error_bag);
+    let source = std::fs::read_to_string(path).unwrap();
+
+    let lexer = Lexer::new(&source, &mut error_bag);
 
     let tokens: Vec<_> = lexer
         .into_iter()
-        .filter(|token| match token.kind {
-            TokenKind::Comment | TokenKind::Invalid => false,
-            _ => true,
-        })
+        .filter(|token| !matches!(token.kind, TokenKind::Comment | TokenKind::Invalid))
         .collect();
 
     error_bag.drain();
 
     let mut parser = Parser::new(tokens);
 
-    let ast = parser.parse();
+    let mut ast = match parser.parse() {
+        Ok(ast) => ast,
+        Err(e) => {
+            error::die(e);
+            return;
+        }
+    };
+
+    Resolver::new(&mut error_bag).resolve(&mut ast);
+
+    if lint {
+        let mut linter = Linter::new(&mut error_bag);
+        linter.levels_from_comments(&source);
+        for (lint, level) in lint_levels {
+            linter.set_level(lint, level);
+        }
+        linter.lint(&ast);
+        error_bag.drain();
+        return;
+    }
+
+    error_bag.drain();
+
+    if optimize {
+        fold_constants(&mut ast);
+    }
 
-    let mut interpreter = Interpreter::new();
-    interpreter.execute(ast);
+    if use_vm || options.uses_vm() || emit_loxc.is_some() {
+        let mut chunk = Compiler::new(&mut error_bag).compile(&ast);
+        error_bag.drain();
+        if optimize {
+            chunk = peephole(chunk);
+        }
+
+        if let Some(output) = emit_loxc {
+            let written = write_chunk(&chunk).and_then(|bytes| {
+                std::fs::write(output, bytes).map_err(|e| {
+                    LoxError::LoadError(format!("Cannot write \x1b[32m{output}\x1b[0m: {e}"))
+                })
+            });
+            if let Err(e) = written {
+                error::die(e);
+            }
+            return;
+        }
+
+        run_chunk(chunk, path, &options, cancel);
+        return;
+    }
+
+    let mut interpreter = Interpreter::with_cancellation(cancel);
+    if let Err(e) = interpreter.execute(ast) {
+        error::die(e);
+    }
     // println!("{res:?}");
     // println!("{tokens:#?}");
     // println!("{ast:#?}");
     // println!("{parser:#?}");
 }
+
+/// Flags that only make sense for the bytecode `VM`
+#[derive(Default)]
+struct RunOptions {
+    disassemble: bool,
+    trace: bool,
+    gc_stress: bool,
+    gc_stats: bool,
+}
+
+impl RunOptions {
+    fn uses_vm(&self) -> bool {
+        self.disassemble || self.trace || self.gc_stress || self.gc_stats
+    }
+}
+
+fn run_chunk(chunk: Chunk, name: &str, options: &RunOptions, cancel: CancellationToken) {
+    if options.disassemble {
+        print!("{}", disassemble_chunk(&chunk, name));
+        return;
+    }
+
+    let mut vm = VM::with_cancellation(cancel);
+    vm.set_trace(options.trace);
+    vm.set_gc_stress(options.gc_stress);
+    let result = vm.interpret(chunk);
+
+    if options.gc_stats {
+        let stats = vm.gc_stats();
+        eprintln!(
+            "gc: {} bytes allocated, {} bytes freed, {} collections, {:?} total pause, {:?} longest pause",
+            stats.bytes_allocated,
+            stats.bytes_freed,
+            stats.collections,
+            stats.total_pause,
+            stats.longest_pause
+        );
+    }
+
+    if result != InterpretResult::InterpretOk {
+        std::process::exit(1);
+    }
+}
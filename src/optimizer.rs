@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{Expression, LiteralKind, Statement},
+    bytecode::{Chunk, OpCode},
+    interpreter::{binary_op, unary_op},
+    lexer::Position,
+    value::{Prototype, Value},
+};
+
+/// Replaces operations on literals with their result, using the same
+/// semantics as the interpreter. Anything that would fail at runtime is
+/// left alone so the error is still reported when and where it happens.
+pub fn fold_constants(statements: &mut [Box<Statement>]) {
+    for statement in statements {
+        match **statement {
+            Statement::Print(ref mut expr, _) | Statement::Expr(ref mut expr) => fold(expr),
+            Statement::Let(_, ref mut initializer, _) => fold(initializer),
+        }
+    }
+}
+
+/// A folded literal takes the position of the operator it replaces
+fn fold(expr: &mut Expression) {
+    let folded = match expr {
+        Expression::Binary(binary) => {
+            fold(&mut binary.lhs);
+            fold(&mut binary.rhs);
+            match (&*binary.lhs, &*binary.rhs) {
+                (Expression::Literal(lhs, _), Expression::Literal(rhs, _)) => {
+                    binary_op(&binary.operator.kind, lhs.into(), rhs.into())
+                        .ok()
+                        .map(|value| (value, binary.operator.span.clone()))
+                }
+                _ => None,
+            }
+        }
+        Expression::Unary(unary) => {
+            fold(&mut unary.rhs);
+            match &*unary.rhs {
+                Expression::Literal(rhs, _) => unary_op(&unary.operator.kind, rhs.into())
+                    .ok()
+                    .map(|value| (value, unary.operator.span.clone())),
+                _ => None,
+            }
+        }
+        Expression::Grouping(inner) => {
+            fold(inner);
+            match **inner {
+                Expression::Literal(ref literal, ref span) => Some((literal.into(), span.clone())),
+                _ => None,
+            }
+        }
+        Expression::Assign(_, value) => {
+            fold(value);
+            None
+        }
+        Expression::Interpolation(parts) => {
+            for part in parts.iter_mut() {
+                fold(part);
+            }
+            let mut string = String::new();
+            let mut start = None;
+            for part in parts.iter() {
+                match **part {
+                    Expression::Literal(ref literal, ref span) => {
+                        start.get_or_insert_with(|| span.clone());
+                        string.push_str(&literal.to_string());
+                    }
+                    _ => return,
+                }
+            }
+            start.map(|span| (Value::String(string.into()), span))
+        }
+        Expression::Literal(..) | Expression::Variable(_) => None,
+    };
+
+    if let Some((value, span)) = folded {
+        if let Some(literal) = literal(&value) {
+            *expr = Expression::Literal(literal, span);
+        }
+    }
+}
+
+fn literal(value: &Value) -> Option<LiteralKind> {
+    match *value {
+        Value::Integer(i) => Some(LiteralKind::Integer(i)),
+        Value::Decimal(d) => Some(LiteralKind::Decimal(d)),
+        Value::Boolean(b) => Some(LiteralKind::Boolean(b)),
+        Value::String(ref s) => Some(LiteralKind::QuotedString(s.to_string())),
+        Value::None => Some(LiteralKind::None),
+        _ => None,
+    }
+}
+
+struct Instruction {
+    offset: usize,
+    op: OpCode,
+    operands: Vec<u8>,
+    span: Position,
+}
+
+impl Instruction {
+    fn operand(&self) -> usize {
+        match *self.operands.as_slice() {
+            [byte] => byte as usize,
+            [high, low] => u16::from_be_bytes([high, low]) as usize,
+            _ => 0,
+        }
+    }
+
+    /// Offset a jump lands on, relative to the chunk it was decoded from
+    fn jump_target(&self) -> Option<usize> {
+        let next = self.offset + 1 + self.operands.len();
+        match self.op {
+            OpCode::OpJump | OpCode::OpJumpIfFalse => Some(next + self.operand()),
+            OpCode::OpLoop => next.checked_sub(self.operand()),
+            _ => None,
+        }
+    }
+}
+
+/// Fuses common instruction sequences into cheaper ones, in this chunk and
+/// every function nested in it. Jumps are re-targeted and no sequence that
+/// a jump lands inside of is touched. Chunks that fail to decode are
+/// returned unchanged for the verifier to reject.
+pub fn peephole(chunk: Chunk) -> Chunk {
+    let Some(instructions) = decode(&chunk) else {
+        return chunk;
+    };
+    let targets: HashSet<usize> = instructions
+        .iter()
+        .filter_map(Instruction::jump_target)
+        .collect();
+
+    // Old offset of every kept or removed instruction, mapped to where the
+    // next surviving instruction starts in the new code
+    let mut moved: HashMap<usize, usize> = HashMap::new();
+    let mut output: Vec<Instruction> = Vec::new();
+    let mut length = 0;
+    let mut rest = instructions.as_slice();
+
+    while !rest.is_empty() {
+        let window = rest
+            .iter()
+            .skip(1)
+            .take(LONGEST_FUSION - 1)
+            .take_while(|next| !targets.contains(&next.offset))
+            .count()
+            + 1;
+        let (consumed, replacement) = fuse(&rest[..window]);
+
+        for instruction in &rest[..consumed] {
+            moved.insert(instruction.offset, length);
+        }
+        for instruction in replacement {
+            length += 1 + instruction.operands.len();
+            output.push(instruction);
+        }
+        rest = &rest[consumed..];
+    }
+    moved.insert(chunk.code.len(), length);
+
+    let mut optimized = Chunk::new();
+    optimized.constant_pool = chunk.constant_pool.iter().map(optimize_constant).collect();
+
+    for mut instruction in output {
+        if let Some(target) = instruction.jump_target() {
+            let here = optimized.code.len() + 3;
+            let Some(&target) = moved.get(&target) else {
+                return chunk;
+            };
+            let operand = match instruction.op {
+                OpCode::OpLoop => here - target,
+                _ => target - here,
+            };
+            instruction.operands = (operand as u16).to_be_bytes().to_vec();
+        }
+
+        optimized.write_op(instruction.op, &instruction.span);
+        for byte in instruction.operands {
+            optimized.write(byte, &instruction.span);
+        }
+    }
+
+    optimized
+}
+
+fn optimize_constant(constant: &Value) -> Value {
+    match constant {
+        Value::Prototype(prototype) => Value::Prototype(Rc::new(Prototype {
+            name: prototype.name,
+            arity: prototype.arity,
+            upvalues: prototype.upvalues.clone(),
+            chunk: peephole(prototype.chunk.clone()),
+        })),
+        other => other.clone(),
+    }
+}
+
+/// Most instructions `fuse` rewrites at once
+const LONGEST_FUSION: usize = 3;
+
+/// Returns how many instructions at the start of `window` were rewritten
+/// and what replaces them. `window` ends before the next jump target.
+fn fuse(window: &[Instruction]) -> (usize, Vec<Instruction>) {
+    let rewrite = |op: OpCode, from: &Instruction| Instruction {
+        offset: from.offset,
+        op,
+        operands: from.operands.clone(),
+        span: from.span.clone(),
+    };
+
+    match window {
+        // `!(a == b)` is `a != b` and the other way around
+        [first, second, ..] if second.op == OpCode::OpNot && first.op == OpCode::OpEqual => {
+            (2, vec![rewrite(OpCode::OpNotEqual, first)])
+        }
+        [first, second, ..] if second.op == OpCode::OpNot && first.op == OpCode::OpNotEqual => {
+            (2, vec![rewrite(OpCode::OpEqual, first)])
+        }
+        // `x = value; print x;` keeps the assigned value instead of
+        // popping it and reading it back
+        [set, pop, get, ..]
+            if pop.op == OpCode::OpPop
+                && set.operands == get.operands
+                && matches!(
+                    (set.op, get.op),
+                    (OpCode::OpSetGlobal, OpCode::OpGetGlobal)
+                        | (OpCode::OpSetLocal, OpCode::OpGetLocal)
+                ) =>
+        {
+            (3, vec![rewrite(set.op, set)])
+        }
+        // Values pushed only to be discarded
+        [push, pop, ..]
+            if pop.op == OpCode::OpPop
+                && matches!(
+                    push.op,
+                    OpCode::OpConstant
+                        | OpCode::OpNone
+                        | OpCode::OpTrue
+                        | OpCode::OpFalse
+                        | OpCode::OpGetLocal
+                ) =>
+        {
+            (2, vec![])
+        }
+        [single, ..] => (1, vec![rewrite(single.op, single)]),
+        [] => (0, vec![]),
+    }
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut positions = chunk.positions();
+    let mut offset = 0;
+
+    while offset < chunk.code.len() {
+        let op = OpCode::try_from(chunk.code[offset]).ok()?;
+        let end = offset + 1 + op.operand_width();
+        let span = positions.next()?;
+        if op.operand_width() > 0 {
+            positions.nth(op.operand_width() - 1);
+        }
+        instructions.push(Instruction {
+            offset,
+            op,
+            operands: chunk.code.get(offset + 1..end)?.to_vec(),
+            span,
+        });
+        offset = end;
+    }
+
+    Some(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{fold_constants, peephole};
+    use crate::{
+        ast::{Expression, LiteralKind, Statement},
+        bytecode::{Chunk, OpCode},
+        lexer::Position,
+        test_support::{compile, resolve},
+    };
+
+    #[allow(clippy::vec_box)]
+    fn folded(program: &str) -> Vec<Box<Statement>> {
+        let mut ast = resolve(program);
+        fold_constants(&mut ast);
+        ast
+    }
+
+    #[test]
+    fn folding_replaces_constant_expressions() {
+        let ast =
+            folded("print 60 * 60 * 24; print -(3); print 1 < 2.5; print 1 / 0; print true + 1;");
+
+        let printed: Vec<_> = ast
+            .iter()
+            .map(|statement| match **statement {
+                Statement::Print(ref expr, _) => expr,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert!(matches!(
+            **printed[0],
+            Expression::Literal(LiteralKind::Integer(86400), _)
+        ));
+        assert!(matches!(
+            **printed[1],
+            Expression::Literal(LiteralKind::Integer(-3), _)
+        ));
+        assert!(matches!(
+            **printed[2],
+            Expression::Literal(LiteralKind::Boolean(true), _)
+        ));
+        // Failing operations stay for the runtime to report
+        assert!(matches!(**printed[3], Expression::Binary(_)));
+        assert!(matches!(**printed[4], Expression::Binary(_)));
+    }
+
+    #[test]
+    fn peephole_fuses_instructions() {
+        let chunk = peephole(compile("let x = 1; x = 2; print x; print !(x == 2);"));
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                0,
+                OpCode::OpDefineGlobal as u8,
+                0,
+                0,
+                OpCode::OpConstant as u8,
+                0,
+                1,
+                OpCode::OpSetGlobal as u8,
+                0,
+                0,
+                OpCode::OpPrint as u8,
+                OpCode::OpGetGlobal as u8,
+                0,
+                0,
+                OpCode::OpConstant as u8,
+                0,
+                1,
+                OpCode::OpNotEqual as u8,
+                OpCode::OpPrint as u8,
+                OpCode::OpNone as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn peephole_retargets_jumps() {
+        let span = Position::new(1, 1);
+        let mut chunk = Chunk::new();
+        for byte in [
+            OpCode::OpTrue as u8,
+            OpCode::OpJumpIfFalse as u8, 0, 5,
+            OpCode::OpTrue as u8,
+            OpCode::OpTrue as u8,
+            OpCode::OpEqual as u8,
+            OpCode::OpNot as u8,
+            OpCode::OpPop as u8,
+            OpCode::OpPop as u8,
+            OpCode::OpNone as u8,
+            OpCode::OpReturn as u8,
+        ] {
+            chunk.write(byte, &span);
+        }
+
+        assert_eq!(
+            peephole(chunk).code,
+            vec![
+                OpCode::OpTrue as u8,
+                OpCode::OpJumpIfFalse as u8, 0, 4,
+                OpCode::OpTrue as u8,
+                OpCode::OpTrue as u8,
+                OpCode::OpNotEqual as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpNone as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn peephole_keeps_up_with_long_scripts() {
+        let program = format!("let x = 0;{}print x;", "x = x + 1;\n".repeat(30_000));
+        let chunk = compile(&program);
+
+        let start = std::time::Instant::now();
+        let optimized = peephole(chunk.clone());
+
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(optimized.positions().count(), optimized.code.len());
+        assert_eq!(
+            optimized.position(optimized.code.len() - 1).map(|p| p.line),
+            Some(30_001)
+        );
+    }
+}
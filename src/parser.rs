@@ -1,305 +1,501 @@
 use crate::{
-    ast::{BinaryExpr, Expression, LiteralKind, Statement, UnaryExpr},
+    ast::{BinaryExpr, Expression, LiteralKind, Statement, UnaryExpr, Variable},
+    error::LoxError,
     lexer::{Token, TokenKind},
 };
 
+/// Deepest nesting of groupings, unary operators and right-hand sides
+/// accepted, so that hostile input cannot overflow the stack of the parser
+pub const MAX_NESTING: usize = 256;
+
+/// Deepest expression tree accepted. Operator chains like `1 + 2 + 3` are
+/// parsed in a loop but still nest the tree one level per operator, and
+/// the passes walking the tree recurse on it.
+pub const MAX_TREE_DEPTH: usize = 4096;
+
 #[derive(Debug)]
 pub struct Parser {
     tokens: Vec<Token>,
     cursor: usize,
+    /// Expression nesting at the token being parsed
+    depth: usize,
+    /// Depth in the expression tree at the token being parsed
+    tree_depth: usize,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, cursor: 0 }
+        Self {
+            tokens,
+            cursor: 0,
+            depth: 0,
+            tree_depth: 0,
+        }
     }
 
-    pub fn to_next_token(&mut self) -> Option<&Token> {
-        let token = self.tokens.get(self.cursor);
+    pub fn advance(&mut self) -> Option<&Token> {
+        let index = self.next_index();
+        let token = self.tokens.get(index);
 
         if token.is_some() {
-            self.cursor += 1;
+            self.cursor = index + 1;
         }
 
         token
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.cursor)
+        self.tokens.get(self.next_index())
+    }
+
+    /// Index of the next token, passing over `///` lines. Only
+    /// `doc_comment` looks at those, in front of a statement.
+    fn next_index(&self) -> usize {
+        let skipped = self.tokens[self.cursor.min(self.tokens.len())..]
+            .iter()
+            .take_while(|token| matches!(token.kind, TokenKind::DocComment(_)))
+            .count();
+        self.cursor + skipped
+    }
+
+    /// Error located at the current token
+    fn error(&self, message: &str) -> LoxError {
+        match self.peek() {
+            Some(token) => LoxError::ParseError(format!(
+                "{message} at line {} column {}",
+                token.span.line, token.span.column
+            )),
+            None => LoxError::ParseError(format!("{message} at end of file")),
+        }
+    }
+
+    /// Goes one level deeper into an expression. Callers restore `depth`
+    /// and `tree_depth` once the nested expression is parsed.
+    fn nest(&mut self) -> Result<(), LoxError> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(self.error(&format!(
+                "Expression nests deeper than {MAX_NESTING} levels"
+            )));
+        }
+        self.deepen()
+    }
+
+    /// Goes one level deeper into the expression tree without recursing,
+    /// as every operator of a chain does
+    fn deepen(&mut self) -> Result<(), LoxError> {
+        self.tree_depth += 1;
+        if self.tree_depth > MAX_TREE_DEPTH {
+            return Err(self.error(&format!(
+                "Expression is deeper than {MAX_TREE_DEPTH} levels"
+            )));
+        }
+        Ok(())
     }
 
-    fn expect_semicolon(&mut self, span: Option<(usize, usize)>) {
+    fn expect_semicolon(&mut self, span: Option<(usize, usize)>) -> Result<(), LoxError> {
         match self.peek().map(|t| &t.kind) {
             Some(&TokenKind::Semicolon) => {
-                self.to_next_token();
+                self.advance();
+                Ok(())
             }
             _ => {
-                if span.is_none() {
-                    crate::error::die(crate::error::LoxError::ParseError(
-                        "Expected semicolon at end of statement at end of file".to_string(),
-                    ));
-                } else {
-                    let span = span.unwrap();
-                    let (line, column) = span;
-                    crate::error::die(crate::error::LoxError::ParseError(format!(
+                if let Some((line, column)) = span {
+                    Err(LoxError::ParseError(format!(
                     "Expected semicolon at end of statement at line \x1b[32m{line}\x1b[0m column \x1b[32m{column}\x1b[0m"
-                )));
+                )))
+                } else {
+                    Err(LoxError::ParseError(
+                        "Expected semicolon at end of statement at end of file".to_string(),
+                    ))
                 }
             }
         }
     }
 
-    pub fn parse(&mut self) -> Vec<Box<Statement>> {
+    /// Stops at the first syntax error
+    pub fn parse(&mut self) -> Result<Vec<Box<Statement>>, LoxError> {
         self.statement()
     }
 
-    pub fn statement(&mut self) -> Vec<Box<Statement>> {
+    pub fn statement(&mut self) -> Result<Vec<Box<Statement>>, LoxError> {
         let mut stmts: Vec<Box<Statement>> = Vec::new();
 
         loop {
-            match self.peek().map(|t| &t.kind) {
-                Some(&TokenKind::Print) => self.print_statement(&mut stmts),
-                Some(&TokenKind::Let) => self.variable_declaration(&mut stmts),
-                Some(_) => stmts.push(Box::new(Statement::Expr(self.expression()))),
-                None => break,
-            }
+            let doc = self.doc_comment();
+            let statement = match self.peek().map(|t| &t.kind) {
+                Some(&TokenKind::Print) => self.print_statement()?,
+                Some(&TokenKind::Let) => self.variable_declaration(doc)?,
+                Some(&TokenKind::Eof) | None => break,
+                Some(_) => Statement::Expr(self.expression()?),
+            };
+            stmts.push(Box::new(statement));
         }
 
-        stmts
+        Ok(stmts)
     }
 
-    fn print_statement(&mut self, stmts: &mut Vec<Box<Statement>>) {
-        self.to_next_token();
-        let value = self.expression();
+    /// Joins the `///` lines in front of the next statement. Only
+    /// declarations keep them.
+    fn doc_comment(&mut self) -> Option<String> {
+        let mut lines = Vec::new();
+        while let Some(TokenKind::DocComment(line)) = self.tokens.get(self.cursor).map(|t| &t.kind)
+        {
+            lines.push(line.clone());
+            self.cursor += 1;
+        }
+
+        match lines.is_empty() {
+            true => None,
+            false => Some(lines.join("\n")),
+        }
+    }
+
+    fn print_statement(&mut self) -> Result<Statement, LoxError> {
+        let keyword = self.advance().unwrap().span.clone();
+        let value = self.expression()?;
 
         let span: Option<(usize, usize)> = self.peek().map(|t| (t.span.line, t.span.column));
 
-        self.expect_semicolon(span);
-        stmts.push(Box::new(Statement::Print(value)));
+        self.expect_semicolon(span)?;
+        Ok(Statement::Print(value, keyword))
     }
 
-    fn variable_declaration(&mut self, stmts: &mut Vec<Box<Statement>>) {
-        self.to_next_token();
-
-        let varname = match self.peek().map(|t| &t.kind) {
-            Some(&TokenKind::Identifier(ref ident)) => ident.clone(),
-            _ => {
-                crate::error::die(crate::error::LoxError::ParseError(
-                    "Expected identifier".to_string(),
-                ));
-                unreachable!()
-            }
+    fn variable_declaration(&mut self, doc: Option<String>) -> Result<Statement, LoxError> {
+        self.advance();
+
+        let variable = match self.peek() {
+            Some(&Token {
+                kind: TokenKind::Identifier(name),
+                ref span,
+                ..
+            }) => Variable {
+                name,
+                span: span.clone(),
+                slot: None,
+            },
+            _ => return Err(self.error("Expected identifier")),
         };
 
-        self.to_next_token();
+        self.advance();
 
         let initializer = match self.peek().map(|t| &t.kind) {
             Some(&TokenKind::Assign) => {
-                self.to_next_token();
-                self.expression()
+                self.advance();
+                self.expression()?
             }
             Some(&TokenKind::Semicolon) => {
-                stmts.push(Box::new(Statement::Let(
-                    varname,
-                    Box::new(Expression::Literal(LiteralKind::None)),
-                )));
-                self.to_next_token();
-                return;
-            }
-            _ => {
-                crate::error::die(crate::error::LoxError::ParseError(
-                    "Expected assign operator".to_string(),
-                ));
-                unreachable!()
+                let none = Expression::Literal(LiteralKind::None, variable.span.clone());
+                self.advance();
+                return Ok(Statement::Let(variable, Box::new(none), doc));
             }
+            _ => return Err(self.error("Expected assign operator")),
         };
 
         let span = self.peek().map(|t| (t.span.line, t.span.column));
-        self.expect_semicolon(span);
-        stmts.push(Box::new(Statement::Let(varname, initializer)));
+        self.expect_semicolon(span)?;
+        Ok(Statement::Let(variable, initializer, doc))
     }
 
-    pub fn expression(&mut self) -> Box<Expression> {
-        self.assignment()
+    pub fn expression(&mut self) -> Result<Box<Expression>, LoxError> {
+        let depth = (self.depth, self.tree_depth);
+        self.nest()?;
+        let expr = self.assignment();
+        (self.depth, self.tree_depth) = depth;
+        expr
     }
 
-    pub fn assignment(&mut self) -> Box<Expression> {
-        let mut expr = self.equality();
+    pub fn assignment(&mut self) -> Result<Box<Expression>, LoxError> {
+        let mut expr = self.equality()?;
 
-        loop {
-            match self.peek().map(|t| &t.kind) {
-                Some(&TokenKind::Assign) => {
-                    let varname = match *expr {
-                        Expression::Literal(val) => match val {
-                            LiteralKind::Identifier(ref s) => s.clone(),
-                            _ => {
-                                crate::error::die(crate::error::LoxError::ParseError(
-                                    "Expected identifier".to_string(),
-                                ));
-                                unreachable!()
-                            }
-                        },
-                        _ => {
-                            crate::error::die(crate::error::LoxError::ParseError(
-                                "Expected literal on assignment".to_string(),
-                            ));
-                            unreachable!()
-                        }
-                    };
-
-                    self.to_next_token();
-                    let value = self.assignment();
-                    expr = Box::new(Expression::Assign(varname, value));
-                    let span = self.peek().map(|t| (t.span.line, t.span.column));
-                    self.expect_semicolon(span);
-                }
-                _ => break,
-            }
+        while let Some(TokenKind::Assign) = self.peek().map(|t| &t.kind) {
+            let variable = match *expr {
+                Expression::Variable(variable) => variable,
+                _ => return Err(self.error("Expected identifier")),
+            };
+
+            self.advance();
+            let value = self.expression()?;
+            expr = Box::new(Expression::Assign(variable, value));
+            let span = self.peek().map(|t| (t.span.line, t.span.column));
+            self.expect_semicolon(span)?;
         }
 
-        expr
+        Ok(expr)
     }
 
-    pub fn equality(&mut self) -> Box<Expression> {
-        let mut expr = self.comparison();
-
-        loop {
-            match self.peek().map(|t| &t.kind) {
-                Some(&TokenKind::NotEqual) | Some(&TokenKind::Equal) => {
-                    let operator = self.to_next_token().cloned().unwrap();
-                    let right = self.comparison();
-                    expr = Box::new(Expression::Binary(BinaryExpr {
-                        operator,
-                        lhs: expr,
-                        rhs: right,
-                    }));
-                }
-                _ => break,
-            };
+    pub fn equality(&mut self) -> Result<Box<Expression>, LoxError> {
+        let tree_depth = self.tree_depth;
+        let mut expr = self.comparison()?;
+
+        while let Some(TokenKind::NotEqual | TokenKind::Equal) = self.peek().map(|t| &t.kind) {
+            self.deepen()?;
+            let operator = self.advance().cloned().unwrap();
+            let right = self.comparison()?;
+            expr = Box::new(Expression::Binary(BinaryExpr {
+                operator,
+                lhs: expr,
+                rhs: right,
+            }));
         }
 
-        expr
+        self.tree_depth = tree_depth;
+        Ok(expr)
     }
 
-    pub fn comparison(&mut self) -> Box<Expression> {
-        let mut expr = self.term();
-
-        loop {
-            match self.peek().map(|t| &t.kind) {
-                Some(&TokenKind::GreaterThan)
-                | Some(&TokenKind::GreaterEqual)
-                | Some(&TokenKind::LessThan)
-                | Some(&TokenKind::LessEqual) => {
-                    let operator = self.to_next_token().cloned().unwrap();
-                    let right = self.term();
-                    expr = Box::new(Expression::Binary(BinaryExpr {
-                        operator,
-                        lhs: expr,
-                        rhs: right,
-                    }));
-                }
-                _ => break,
-            };
+    pub fn comparison(&mut self) -> Result<Box<Expression>, LoxError> {
+        let tree_depth = self.tree_depth;
+        let mut expr = self.term()?;
+
+        while let Some(
+            TokenKind::GreaterThan
+            | TokenKind::GreaterEqual
+            | TokenKind::LessThan
+            | TokenKind::LessEqual,
+        ) = self.peek().map(|t| &t.kind)
+        {
+            self.deepen()?;
+            let operator = self.advance().cloned().unwrap();
+            let right = self.term()?;
+            expr = Box::new(Expression::Binary(BinaryExpr {
+                operator,
+                lhs: expr,
+                rhs: right,
+            }));
         }
 
-        expr
+        self.tree_depth = tree_depth;
+        Ok(expr)
     }
 
-    pub fn term(&mut self) -> Box<Expression> {
-        let mut expr = self.factor();
-
-        loop {
-            match self.peek().map(|t| &t.kind) {
-                Some(&TokenKind::Minus) | Some(&TokenKind::Plus) => {
-                    let operator = self.to_next_token().cloned().unwrap();
-                    let right = self.factor();
-                    expr = Box::new(Expression::Binary(BinaryExpr {
-                        operator,
-                        lhs: expr,
-                        rhs: right,
-                    }));
-                }
-                _ => break,
-            };
+    pub fn term(&mut self) -> Result<Box<Expression>, LoxError> {
+        let tree_depth = self.tree_depth;
+        let mut expr = self.factor()?;
+
+        while let Some(TokenKind::Minus | TokenKind::Plus) = self.peek().map(|t| &t.kind) {
+            self.deepen()?;
+            let operator = self.advance().cloned().unwrap();
+            let right = self.factor()?;
+            expr = Box::new(Expression::Binary(BinaryExpr {
+                operator,
+                lhs: expr,
+                rhs: right,
+            }));
         }
 
-        expr
+        self.tree_depth = tree_depth;
+        Ok(expr)
     }
 
-    pub fn factor(&mut self) -> Box<Expression> {
-        let mut expr = self.unary();
-
-        loop {
-            match self.peek().map(|t| &t.kind) {
-                Some(&TokenKind::ForwardSlash)
-                | Some(&TokenKind::Asterisk)
-                | Some(&TokenKind::Percentage) => {
-                    let operator = self.to_next_token().cloned().unwrap();
-                    let right = self.unary();
-                    expr = Box::new(Expression::Binary(BinaryExpr {
-                        operator,
-                        lhs: expr,
-                        rhs: right,
-                    }));
-                }
-                _ => break,
-            };
+    pub fn factor(&mut self) -> Result<Box<Expression>, LoxError> {
+        let tree_depth = self.tree_depth;
+        let mut expr = self.unary()?;
+
+        while let Some(TokenKind::ForwardSlash | TokenKind::Asterisk | TokenKind::Percentage) =
+            self.peek().map(|t| &t.kind)
+        {
+            self.deepen()?;
+            let operator = self.advance().cloned().unwrap();
+            let right = self.unary()?;
+            expr = Box::new(Expression::Binary(BinaryExpr {
+                operator,
+                lhs: expr,
+                rhs: right,
+            }));
         }
 
-        expr
+        self.tree_depth = tree_depth;
+        Ok(expr)
     }
 
-    pub fn unary(&mut self) -> Box<Expression> {
+    pub fn unary(&mut self) -> Result<Box<Expression>, LoxError> {
         match self.peek().map(|t| &t.kind) {
             Some(&TokenKind::Bang) | Some(TokenKind::Minus) => {
-                let operator = self.to_next_token().cloned().unwrap();
-                let right = self.unary();
-                return Box::new(Expression::Unary(UnaryExpr {
+                let depth = (self.depth, self.tree_depth);
+                self.nest()?;
+                let operator = self.advance().cloned().unwrap();
+                let right = self.unary()?;
+                (self.depth, self.tree_depth) = depth;
+                Ok(Box::new(Expression::Unary(UnaryExpr {
                     operator,
                     rhs: right,
-                }));
+                })))
             }
-            Some(_) => self.primary(),
-            None => panic!("Fix this"),
+            _ => self.primary(),
         }
     }
 
-    pub fn primary(&mut self) -> Box<Expression> {
+    pub fn primary(&mut self) -> Result<Box<Expression>, LoxError> {
+        let span = match self.peek() {
+            Some(token) => token.span.clone(),
+            None => return Err(self.error("Expected expression before end of file")),
+        };
+        let literal = |kind| Box::new(Expression::Literal(kind, span.clone()));
         let token: Box<Expression> = match self.peek().map(|t| &t.kind) {
-            Some(&TokenKind::True) => Box::new(Expression::Literal(LiteralKind::Boolean(true))),
-            Some(&TokenKind::False) => Box::new(Expression::Literal(LiteralKind::Boolean(false))),
-            Some(&TokenKind::None) => Box::new(Expression::Literal(LiteralKind::None)),
-            Some(&TokenKind::Integer(i)) => Box::new(Expression::Literal(LiteralKind::Integer(i))),
-            Some(&TokenKind::Decimal(d)) => Box::new(Expression::Literal(LiteralKind::Decimal(d))),
-            Some(&TokenKind::QuotedString(ref s)) => {
-                Box::new(Expression::Literal(LiteralKind::QuotedString(s.clone())))
-            }
-            Some(&TokenKind::Identifier(ref s)) => {
-                Box::new(Expression::Literal(LiteralKind::Identifier(s.clone())))
-            }
+            Some(&TokenKind::True) => literal(LiteralKind::Boolean(true)),
+            Some(&TokenKind::False) => literal(LiteralKind::Boolean(false)),
+            Some(&TokenKind::None) => literal(LiteralKind::None),
+            Some(&TokenKind::Integer(i)) => literal(LiteralKind::Integer(i)),
+            Some(&TokenKind::Decimal(d)) => literal(LiteralKind::Decimal(d)),
+            Some(TokenKind::QuotedString(s)) => literal(LiteralKind::QuotedString(s.clone())),
+            Some(&TokenKind::InterpolatedString(_)) => self.interpolation()?,
+            Some(&TokenKind::Identifier(name)) => Box::new(Expression::Variable(Variable {
+                name,
+                span: span.clone(),
+                slot: None,
+            })),
             Some(&TokenKind::OpenParen) => {
-                self.to_next_token();
-                let expr = self.expression();
+                self.advance();
+                let expr = self.expression()?;
                 match self.peek().map(|t| &t.kind) {
                     Some(&TokenKind::CloseParen) => {}
-                    Some(_) => panic!("Unclosed parentheses"),
-                    None => {}
+                    Some(&TokenKind::Eof) | None => {
+                        return Err(self.error("Expected \x1b[32m)\x1b[0m before end of file"))
+                    }
+                    Some(_) => {
+                        return Err(self.error("Expected \x1b[32m)\x1b[0m to close parentheses"))
+                    }
                 };
 
                 Box::new(Expression::Grouping(expr))
             }
+            Some(&TokenKind::Eof) | None => {
+                return Err(self.error("Expected expression before end of file"))
+            }
             Some(other) => {
-                let span = self.peek().cloned().unwrap().span;
-                crate::error::die(crate::error::LoxError::ParseError(format!(
-                    "Expected primary expression got \x1b[32m{:?}\x1b[0m at line {} column {}",
-                    other, span.line, span.column,
-                )));
-                unreachable!()
+                return Err(self.error(&format!(
+                    "Expected primary expression got \x1b[32m{:?}\x1b[0m",
+                    other
+                )))
             }
-            None => panic!("Unexpected Eof"),
         };
 
-        self.to_next_token();
-        token
+        self.advance();
+        Ok(token)
+    }
+
+    /// `"a ${x} b"` arrives as `InterpolatedString("a ")`, the tokens of `x`
+    /// and `QuotedString(" b")`. Stops on the closing part, which `primary`
+    /// consumes.
+    fn interpolation(&mut self) -> Result<Box<Expression>, LoxError> {
+        let mut parts = Vec::new();
+
+        while let Some(Token {
+            kind: TokenKind::InterpolatedString(part),
+            span,
+            ..
+        }) = self.peek().cloned()
+        {
+            parts.push(Box::new(Expression::Literal(
+                LiteralKind::QuotedString(part),
+                span,
+            )));
+            self.advance();
+            parts.push(self.expression()?);
+        }
+
+        match self.peek() {
+            Some(&Token {
+                kind: TokenKind::QuotedString(ref part),
+                ref span,
+                ..
+            }) => parts.push(Box::new(Expression::Literal(
+                LiteralKind::QuotedString(part.clone()),
+                span.clone(),
+            ))),
+            _ => return Err(self.error("Expected \x1b[32m}\x1b[0m to close string interpolation")),
+        }
+
+        Ok(Box::new(Expression::Interpolation(parts)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Parser, MAX_TREE_DEPTH};
+    use crate::{
+        ast::Statement,
+        error::{ErrorBag, LoxError},
+        lexer::{Lexer, TokenKind},
+    };
+
+    #[test]
+    fn parser_attaches_doc_comments_to_declarations() {
+        let program =
+            "/// Seconds\n/// in a day\nlet day = 86400;\n/// dropped\nprint day;\nlet x = 1;";
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag)
+            .filter(|token| !matches!(token.kind, TokenKind::Comment))
+            .collect();
+
+        let ast = Parser::new(tokens).parse().unwrap();
+
+        let docs: Vec<_> = ast
+            .iter()
+            .filter_map(|statement| match **statement {
+                Statement::Let(_, _, ref doc) => Some(doc.as_deref()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(docs, vec![Some("Seconds\nin a day"), None]);
+    }
+
+    #[test]
+    fn parser_skips_doc_comments_inside_statements() {
+        let program = "let x = 1 /// note\n;\nprint -/// sign\nx;";
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+        let ast = Parser::new(tokens).parse().unwrap();
+
+        assert_eq!(ast.len(), 2);
+        assert!(matches!(*ast[0], Statement::Let(_, _, None)));
+    }
+
+    #[test]
+    fn parser_reports_malformed_input_as_errors() {
+        let nested = format!("print {}1{};", "(".repeat(300), ")".repeat(300));
+        let chained = format!("print 1{};", " + 1".repeat(MAX_TREE_DEPTH + 1));
+        let programs = [
+            "print (1",
+            "print (1 2);",
+            "print -",
+            "let = 1;",
+            "let x 1;",
+            "1 = 2;",
+            "print \"a${1 2}\";",
+            "print 1",
+            nested.as_str(),
+            chained.as_str(),
+        ];
+
+        for program in programs {
+            let mut error_bag = ErrorBag { errors: vec![] };
+            let tokens: Vec<_> = Lexer::new(program, &mut error_bag).collect();
+
+            let result = Parser::new(tokens).parse();
+
+            assert!(
+                matches!(result, Err(LoxError::ParseError(_))),
+                "{program:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parser_accepts_long_operator_chains() {
+        let programs = [
+            format!("print 1{};", " + 1".repeat(1000)),
+            format!("print 1{};", " * 2 - 1 < 3 == true".repeat(250)),
+            format!("print {}1{};", "(".repeat(200), " + 1)".repeat(200)),
+        ];
+
+        for program in programs {
+            let mut error_bag = ErrorBag { errors: vec![] };
+            let tokens: Vec<_> = Lexer::new(&program, &mut error_bag).collect();
+
+            assert!(Parser::new(tokens).parse().is_ok(), "{program:?}");
+        }
     }
 }
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Expression, Slot, Statement, Variable},
+    error::{ErrorBag, LoxError},
+    intern::Symbol,
+};
+
+/// Binding index inside its scope and whether its initializer has finished
+type Scope = HashMap<Symbol, (usize, bool)>;
+
+/// Static pass run between parsing and execution. Binds every variable
+/// reference to a `Slot` and reports scope errors before any code runs.
+pub struct Resolver<'a> {
+    scopes: Vec<Scope>,
+    pub error_bag: &'a mut ErrorBag,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(error_bag: &'a mut ErrorBag) -> Self {
+        Self {
+            scopes: vec![Scope::new()],
+            error_bag,
+        }
+    }
+
+    pub fn resolve(&mut self, statements: &mut [Box<Statement>]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::Print(expr, _) | Statement::Expr(expr) => self.expression(expr),
+            Statement::Let(variable, initializer, _) => {
+                self.declare(variable);
+                self.expression(initializer);
+                self.define(variable);
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &mut Expression) {
+        match expr {
+            Expression::Binary(binary) => {
+                self.expression(&mut binary.lhs);
+                self.expression(&mut binary.rhs);
+            }
+            Expression::Unary(unary) => self.expression(&mut unary.rhs),
+            Expression::Grouping(inner) => self.expression(inner),
+            Expression::Literal(..) => {}
+            Expression::Variable(variable) => self.lookup(variable),
+            Expression::Assign(variable, value) => {
+                self.expression(value);
+                self.assign(variable);
+            }
+            Expression::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part);
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, variable: &mut Variable) {
+        let scope = self.scopes.last_mut().unwrap();
+
+        if scope.contains_key(&variable.name) {
+            self.error_bag.errors.push(LoxError::ResolveError(format!(
+                "Variable \x1b[32m{}\x1b[0m is already declared in this scope at line {} column {}",
+                variable.name, variable.span.line, variable.span.column
+            )));
+            return;
+        }
+
+        let index = scope.len();
+        scope.insert(variable.name, (index, false));
+        variable.slot = Some(Slot { depth: 0, index });
+    }
+
+    fn define(&mut self, variable: &Variable) {
+        if let Some(binding) = self.scopes.last_mut().unwrap().get_mut(&variable.name) {
+            binding.1 = true;
+        }
+    }
+
+    fn find(&self, name: Symbol) -> Option<(Slot, bool)> {
+        self.scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                scope
+                    .get(&name)
+                    .map(|&(index, defined)| (Slot { depth, index }, defined))
+            })
+    }
+
+    fn lookup(&mut self, variable: &mut Variable) {
+        match self.find(variable.name) {
+            Some((slot, false)) if slot.depth == 0 => {
+                self.error_bag.errors.push(LoxError::ResolveError(format!(
+                    "Cannot read \x1b[32m{}\x1b[0m in its own initializer at line {} column {}",
+                    variable.name, variable.span.line, variable.span.column
+                )));
+            }
+            Some((slot, _)) => variable.slot = Some(slot),
+            None => {
+                self.error_bag.errors.push(LoxError::ResolveError(format!(
+                    "Use of undeclared identifier \x1b[32m{}\x1b[0m at line {} column {}",
+                    variable.name, variable.span.line, variable.span.column
+                )));
+            }
+        }
+    }
+
+    /// Assigning to a name that was never declared creates a global
+    fn assign(&mut self, variable: &mut Variable) {
+        if let Some((slot, _)) = self.find(variable.name) {
+            variable.slot = Some(slot);
+            return;
+        }
+
+        let depth = self.scopes.len() - 1;
+        let globals = &mut self.scopes[0];
+        let index = globals.len();
+        globals.insert(variable.name, (index, true));
+        variable.slot = Some(Slot { depth, index });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{error::ErrorBag, test_support::resolve_into};
+
+    fn resolve(program: &str) -> usize {
+        let mut error_bag = ErrorBag { errors: vec![] };
+        resolve_into(program, &mut error_bag);
+        error_bag.errors.len()
+    }
+
+    #[test]
+    fn resolver_accepts_declared_variables() {
+        assert_eq!(resolve("let x = 1; let y = x + 1; y = x; print y;"), 0);
+        assert_eq!(resolve("z = 1; print z;"), 0);
+    }
+
+    #[test]
+    fn resolver_reports_scope_errors() {
+        assert_eq!(resolve("print y;"), 1);
+        assert_eq!(resolve("let x = x + 1;"), 1);
+        assert_eq!(resolve("let x = 1; let x = 2;"), 1);
+        assert_eq!(resolve("y = y;"), 1);
+    }
+}
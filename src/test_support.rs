@@ -0,0 +1,59 @@
+//! Pipeline setup shared by the unit tests
+
+use crate::{
+    ast::Statement,
+    bytecode::Chunk,
+    compiler::Compiler,
+    error::ErrorBag,
+    lexer::{Lexer, TokenKind},
+    parser::Parser,
+    resolver::Resolver,
+};
+
+/// Lexes, parses and resolves `program`, leaving any lexer and resolver
+/// errors in `error_bag`. Comments are dropped like the CLI drops them.
+/// Panics if the program does not parse.
+#[allow(clippy::vec_box)]
+pub fn resolve_into(program: &str, error_bag: &mut ErrorBag) -> Vec<Box<Statement>> {
+    let tokens: Vec<_> = Lexer::new(program, error_bag)
+        .filter(|token| !matches!(token.kind, TokenKind::Comment | TokenKind::Invalid))
+        .collect();
+    let mut ast = Parser::new(tokens).parse().unwrap();
+    Resolver::new(error_bag).resolve(&mut ast);
+    ast
+}
+
+/// Resolves a program that has no errors
+#[allow(clippy::vec_box)]
+pub fn resolve(program: &str) -> Vec<Box<Statement>> {
+    let mut error_bag = ErrorBag { errors: vec![] };
+    let ast = resolve_into(program, &mut error_bag);
+    assert!(error_bag.errors.is_empty(), "{:?}", error_bag.errors);
+    ast
+}
+
+/// Compiles a program that has no errors, without optimizing it
+pub fn compile(program: &str) -> Chunk {
+    let mut error_bag = ErrorBag { errors: vec![] };
+    let chunk = Compiler::new(&mut error_bag).compile(&resolve(program));
+    assert!(error_bag.errors.is_empty(), "{:?}", error_bag.errors);
+    chunk
+}
+
+/// Hand-rolled xorshift keeps randomized tests deterministic without a
+/// dependency
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// A number in `0..limit`
+    pub fn below(&mut self, limit: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % limit as u64) as usize
+    }
+}
@@ -0,0 +1,74 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{ast::LiteralKind, bytecode::Chunk, intern::Symbol};
+
+/// Runtime representation of every value a Lox program can produce.
+/// Heap objects are reference counted so copies are cheap.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Integer(isize),
+    Decimal(f64),
+    Boolean(bool),
+    String(Rc<str>),
+    Prototype(Rc<Prototype>),
+    Closure(Rc<Closure>),
+    None,
+}
+
+/// Function compiled to bytecode, stored in the constant pool of the chunk
+/// that declares it. The `VM` turns it into a `Closure` at runtime.
+#[derive(Debug)]
+pub struct Prototype {
+    pub name: Symbol,
+    pub arity: usize,
+    pub upvalues: Vec<UpvalueSource>,
+    pub chunk: Chunk,
+}
+
+/// Where a closure captures an upvalue from when it is created: a local
+/// slot of the enclosing frame or one of the enclosing closure's upvalues
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpvalueSource {
+    pub is_local: bool,
+    pub index: u8,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub prototype: Rc<Prototype>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// A captured variable. It points at a stack slot while the declaring
+/// frame is alive and owns the value once that frame returns.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+impl From<&LiteralKind> for Value {
+    fn from(literal: &LiteralKind) -> Self {
+        match *literal {
+            LiteralKind::Integer(i) => Self::Integer(i),
+            LiteralKind::Decimal(d) => Self::Decimal(d),
+            LiteralKind::Boolean(b) => Self::Boolean(b),
+            LiteralKind::QuotedString(ref s) => Self::String(s.as_str().into()),
+            LiteralKind::None => Self::None,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::Integer(i) => write!(f, "{}", i),
+            Self::Decimal(d) => write!(f, "{}", d),
+            Self::Boolean(b) => write!(f, "{}", b),
+            Self::String(ref s) => write!(f, "{}", s),
+            Self::Prototype(ref prototype) => write!(f, "<fn {}>", prototype.name),
+            Self::Closure(ref closure) => write!(f, "<fn {}>", closure.prototype.name),
+            Self::None => write!(f, "None"),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{
+    bytecode::{Chunk, OpCode},
+    error::LoxError,
+    value::Value,
+};
+
+/// Checks that a chunk can run without the `VM` reading out of bounds:
+/// every opcode and operand is complete, constant indices exist, jumps
+/// land on instruction boundaries, the stack never underflows and local
+/// slots and upvalues refer to values that exist. Every path has to end in
+/// `OpReturn`. Nested functions in the constant pool are verified too.
+pub fn verify(chunk: &Chunk) -> Result<(), LoxError> {
+    // The script runs like a function without parameters or upvalues
+    verify_function(chunk, 1, 0)
+}
+
+/// `reserved` is the callee plus its arguments, present when the frame starts
+fn verify_function(chunk: &Chunk, reserved: usize, upvalue_count: usize) -> Result<(), LoxError> {
+    for constant in &chunk.constant_pool {
+        if let Value::Prototype(prototype) = constant {
+            verify_function(
+                &prototype.chunk,
+                prototype.arity + 1,
+                prototype.upvalues.len(),
+            )?;
+        }
+    }
+
+    let boundaries = instruction_boundaries(chunk)?;
+
+    // Stack depth on entry to every instruction reached so far
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    let mut pending: Vec<(usize, usize)> = vec![(0, reserved)];
+
+    while let Some((offset, depth)) = pending.pop() {
+        if offset == chunk.code.len() {
+            return Err(invalid(offset, "execution runs past the end of the code"));
+        }
+
+        match depths[offset] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(invalid(
+                    offset,
+                    &format!("stack depth is {depth} on one path and {known} on another"),
+                ))
+            }
+            None => depths[offset] = Some(depth),
+        }
+
+        let op = OpCode::try_from(chunk.code[offset]).unwrap();
+        let next = offset + 1 + op.operand_width();
+
+        let (pops, pushes) = match op {
+            OpCode::OpCall => (chunk.code[offset + 1] as usize + 1, 1),
+            OpCode::OpInterpolate => (chunk.code[offset + 1] as usize, 1),
+            _ => stack_effect(op),
+        };
+        if depth < reserved + pops {
+            return Err(invalid(
+                offset,
+                &format!(
+                    "{:?} needs {pops} values but the frame holds {}",
+                    op,
+                    depth - reserved
+                ),
+            ));
+        }
+        let after = depth - pops + pushes;
+
+        match op {
+            OpCode::OpConstant => {
+                let index = chunk.read_u16(offset + 1) as usize;
+                if index >= chunk.constant_pool.len() {
+                    return Err(invalid(
+                        offset,
+                        &format!(
+                            "constant {index} is outside a pool of {}",
+                            chunk.constant_pool.len()
+                        ),
+                    ));
+                }
+            }
+            OpCode::OpClosure => {
+                let index = chunk.read_u16(offset + 1) as usize;
+                let Some(Value::Prototype(prototype)) = chunk.constant_pool.get(index) else {
+                    return Err(invalid(
+                        offset,
+                        &format!("constant {index} is not a function"),
+                    ));
+                };
+
+                for source in &prototype.upvalues {
+                    let index = source.index as usize;
+                    if (source.is_local && index >= depth)
+                        || (!source.is_local && index >= upvalue_count)
+                    {
+                        return Err(invalid(
+                            offset,
+                            &format!("{} captures a missing variable", prototype.name),
+                        ));
+                    }
+                }
+            }
+            OpCode::OpGetUpvalue | OpCode::OpSetUpvalue => {
+                let index = chunk.code[offset + 1] as usize;
+                if index >= upvalue_count {
+                    return Err(invalid(
+                        offset,
+                        &format!("upvalue {index} is outside {upvalue_count} upvalues"),
+                    ));
+                }
+            }
+            OpCode::OpGetLocal | OpCode::OpSetLocal => {
+                let slot = chunk.code[offset + 1] as usize;
+                if slot >= depth {
+                    return Err(invalid(
+                        offset,
+                        &format!("local slot {slot} is outside a stack of {depth}"),
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        match op {
+            OpCode::OpReturn => {}
+            OpCode::OpJump | OpCode::OpJumpIfFalse | OpCode::OpLoop => {
+                let jump = chunk.read_u16(offset + 1) as usize;
+                let target = match op {
+                    OpCode::OpLoop => next.checked_sub(jump),
+                    _ => Some(next + jump),
+                };
+
+                match target {
+                    Some(target) if boundaries.get(target) == Some(&true) => {
+                        pending.push((target, after));
+                    }
+                    _ => return Err(invalid(offset, "jump target is not an instruction")),
+                }
+
+                if op == OpCode::OpJumpIfFalse {
+                    pending.push((next, after));
+                }
+            }
+            _ => pending.push((next, after)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks the offset of every instruction, rejecting unknown or truncated ones
+fn instruction_boundaries(chunk: &Chunk) -> Result<Vec<bool>, LoxError> {
+    let mut boundaries = vec![false; chunk.code.len()];
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let byte = chunk.code[offset];
+        let Ok(op) = OpCode::try_from(byte) else {
+            return Err(invalid(offset, &format!("unknown opcode {byte}")));
+        };
+
+        boundaries[offset] = true;
+        offset += 1 + op.operand_width();
+        if offset > chunk.code.len() {
+            return Err(invalid(
+                offset,
+                &format!("{:?} is missing its operands", op),
+            ));
+        }
+    }
+
+    Ok(boundaries)
+}
+
+/// Values popped and pushed by each instruction
+fn stack_effect(op: OpCode) -> (usize, usize) {
+    match op {
+        OpCode::OpConstant
+        | OpCode::OpNone
+        | OpCode::OpTrue
+        | OpCode::OpFalse
+        | OpCode::OpGetGlobal
+        | OpCode::OpGetLocal
+        | OpCode::OpGetUpvalue
+        | OpCode::OpClosure => (0, 1),
+        OpCode::OpPop
+        | OpCode::OpDefineGlobal
+        | OpCode::OpPrint
+        | OpCode::OpCloseUpvalue
+        | OpCode::OpReturn => (1, 0),
+        OpCode::OpSetGlobal
+        | OpCode::OpSetLocal
+        | OpCode::OpSetUpvalue
+        | OpCode::OpNot
+        | OpCode::OpNegate
+        | OpCode::OpJumpIfFalse => (1, 1),
+        OpCode::OpEqual
+        | OpCode::OpNotEqual
+        | OpCode::OpGreater
+        | OpCode::OpGreaterEqual
+        | OpCode::OpLess
+        | OpCode::OpLessEqual
+        | OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpModulo => (2, 1),
+        OpCode::OpJump | OpCode::OpLoop => (0, 0),
+        // Depend on their count operand
+        OpCode::OpCall | OpCode::OpInterpolate => (1, 1),
+    }
+}
+
+fn invalid(offset: usize, reason: &str) -> LoxError {
+    LoxError::VerifyError(format!(
+        "Invalid bytecode at offset \x1b[32m{offset:04}\x1b[0m: {reason}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::verify;
+    use crate::{
+        bytecode::{Chunk, OpCode},
+        lexer::Position,
+        value::Value,
+    };
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let span = Position::new(1, 1);
+        let mut chunk = Chunk::new();
+        chunk.constant_pool.push(Value::Integer(1));
+        for &byte in code {
+            chunk.write(byte, &span);
+        }
+        chunk
+    }
+
+    #[test]
+    fn verifier_accepts_well_formed_chunks() {
+        let constant = OpCode::OpConstant as u8;
+        let code = [
+            constant,
+            0,
+            0,
+            OpCode::OpJumpIfFalse as u8,
+            0,
+            4,
+            constant,
+            0,
+            0,
+            OpCode::OpPop as u8,
+            OpCode::OpPrint as u8,
+            OpCode::OpNone as u8,
+            OpCode::OpReturn as u8,
+        ];
+
+        assert!(verify(&chunk(&code)).is_ok());
+    }
+
+    #[test]
+    fn verifier_rejects_malformed_chunks() {
+        let constant = OpCode::OpConstant as u8;
+
+        assert!(verify(&chunk(&[constant, 0, 1])).is_err());
+        assert!(verify(&chunk(&[constant, 0])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpPop as u8])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpJump as u8, 0, 1, constant, 0, 0])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpGetLocal as u8, 1])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpGetUpvalue as u8, 0])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpReturn as u8])).is_err());
+        assert!(verify(&chunk(&[255])).is_err());
+
+        // Paths that run off the end of the code without returning
+        assert!(verify(&chunk(&[])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpNone as u8])).is_err());
+        assert!(verify(&chunk(&[OpCode::OpJump as u8, 0, 0])).is_err());
+        assert!(verify(&chunk(&[
+            OpCode::OpJump as u8,
+            0,
+            9,
+            OpCode::OpReturn as u8
+        ]))
+        .is_err());
+    }
+}
@@ -1,28 +1,645 @@
-#[allow(unused)]
-use crate::bytecode::{Chunk, OpCode};
+use std::{cell::RefCell, rc::Rc};
 
-#[allow(unused)]
+use crate::{
+    bytecode::{Chunk, OpCode},
+    cancel::CancellationToken,
+    disassembler::disassemble_instruction,
+    error::LoxError,
+    gc::{GcStats, Heap},
+    intern::Symbol,
+    interpreter::{binary_op, unary_op},
+    lexer::TokenKind,
+    value::{Closure, Prototype, Upvalue, Value},
+    verifier::verify,
+};
+
+/// Number of instructions dispatched between two cancellation checks
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+
+/// Deepest call nesting before the VM reports a stack overflow
+pub const FRAMES_MAX: usize = 256;
+
+#[derive(Debug, PartialEq)]
 pub enum InterpretResult {
     InterpretOk,
     RuntimeError,
     CompileError,
+    Interrupted,
+}
+
+/// One active function call. Its locals start at `slot_base` on the value
+/// stack, where slot 0 holds the closure being called.
+struct CallFrame {
+    closure: Rc<Closure>,
+    /// Saved instruction pointer of a caller while its callee runs
+    ip: usize,
+    slot_base: usize,
 }
 
-#[allow(unused)]
 pub struct VM {
-    chunck: Option<Chunk>,
+    frames: Vec<CallFrame>,
+    /// Instruction pointer of the innermost frame
     ip: usize,
+    /// Offset of the instruction being executed, used to locate errors
+    instruction_start: usize,
+    stack: Vec<Value>,
+    pub(crate) globals: Vec<Value>,
+    /// Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    heap: Heap,
+    cancel: CancellationToken,
+    /// Print the stack and the next instruction before dispatching it
+    trace: bool,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
-    #[allow(unused)]
     pub fn new() -> Self {
+        Self::with_cancellation(CancellationToken::new())
+    }
+
+    pub fn with_cancellation(cancel: CancellationToken) -> Self {
         Self {
-            chunck: None,
+            frames: Vec::new(),
             ip: 0,
+            instruction_start: 0,
+            stack: Vec::new(),
+            globals: Vec::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
+            cancel,
+            trace: false,
+        }
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Collect garbage before every allocation instead of on a threshold
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    /// Chunks are verified first, so the dispatch loop can trust operands
+    /// and stack depth even for chunks loaded from disk
+    pub fn interpret(&mut self, chunck: Chunk) -> InterpretResult {
+        if let Err(e) = verify(&chunck) {
+            crate::error::report(e);
+            return InterpretResult::CompileError;
+        }
+
+        let script = Rc::new(Closure {
+            prototype: Rc::new(Prototype {
+                name: Symbol::intern("script"),
+                arity: 0,
+                upvalues: Vec::new(),
+                chunk: chunck,
+            }),
+            upvalues: Vec::new(),
+        });
+
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.stack.push(Value::Closure(Rc::clone(&script)));
+        self.frames.push(CallFrame {
+            closure: script,
+            ip: 0,
+            slot_base: 0,
+        });
+        self.ip = 0;
+
+        match self.run() {
+            Ok(()) => InterpretResult::InterpretOk,
+            Err(e) => {
+                let result = match e {
+                    LoxError::Interrupted(_) => InterpretResult::Interrupted,
+                    _ => InterpretResult::RuntimeError,
+                };
+                crate::error::report(e);
+                result
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<(), LoxError> {
+        self.dispatch().map_err(|e| match e {
+            LoxError::RuntimeError(message) => {
+                let chunck = &self.frame().closure.prototype.chunk;
+                match chunck.position(self.instruction_start) {
+                    Some(span) => LoxError::RuntimeError(format!(
+                        "{message} at line {} column {}",
+                        span.line, span.column
+                    )),
+                    None => LoxError::RuntimeError(message),
+                }
+            }
+            other => other,
+        })
+    }
+
+    fn dispatch(&mut self) -> Result<(), LoxError> {
+        let mut dispatched: usize = 0;
+        let mut closure = Rc::clone(&self.frame().closure);
+
+        loop {
+            let chunck = &closure.prototype.chunk;
+
+            if dispatched.is_multiple_of(CANCEL_CHECK_INTERVAL) {
+                self.cancel.check()?;
+            }
+            dispatched += 1;
+
+            self.instruction_start = self.ip;
+            if self.trace {
+                self.trace_instruction(chunck);
+            }
+            let byte = chunck.code[self.ip];
+            let Ok(instruction) = OpCode::try_from(byte) else {
+                return Err(LoxError::RuntimeError(format!(
+                    "Unknown opcode \x1b[34m{byte}\x1b[0m"
+                )));
+            };
+            self.ip += 1;
+
+            match instruction {
+                OpCode::OpConstant => {
+                    let index = self.read_u16(chunck);
+                    self.stack.push(chunck.constant_pool[index].clone());
+                }
+                OpCode::OpNone => self.stack.push(Value::None),
+                OpCode::OpTrue => self.stack.push(Value::Boolean(true)),
+                OpCode::OpFalse => self.stack.push(Value::Boolean(false)),
+                OpCode::OpPop => {
+                    self.pop();
+                }
+                OpCode::OpDefineGlobal => {
+                    let index = self.read_u16(chunck);
+                    let value = self.pop();
+                    self.set_global(index, value);
+                }
+                OpCode::OpGetGlobal => {
+                    let index = self.read_u16(chunck);
+                    match self.globals.get(index) {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => {
+                            return Err(LoxError::RuntimeError(format!(
+                                "Use of undeclared global in slot \x1b[32m{index}\x1b[0m"
+                            )))
+                        }
+                    }
+                }
+                OpCode::OpSetGlobal => {
+                    let index = self.read_u16(chunck);
+                    let value = self.peek().clone();
+                    self.set_global(index, value);
+                }
+                OpCode::OpGetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte(chunck);
+                    self.stack.push(self.stack[slot].clone());
+                }
+                OpCode::OpSetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte(chunck);
+                    self.stack[slot] = self.peek().clone();
+                }
+                OpCode::OpGetUpvalue => {
+                    let index = self.read_byte(chunck);
+                    let value = match *closure.upvalues[index].borrow() {
+                        Upvalue::Open(slot) => self.stack[slot].clone(),
+                        Upvalue::Closed(ref value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::OpSetUpvalue => {
+                    let index = self.read_byte(chunck);
+                    let value = self.peek().clone();
+                    let mut upvalue = closure.upvalues[index].borrow_mut();
+                    match &mut *upvalue {
+                        &mut Upvalue::Open(slot) => self.stack[slot] = value,
+                        closed => *closed = Upvalue::Closed(value),
+                    }
+                }
+                OpCode::OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::OpEqual => self.binary(&TokenKind::Equal)?,
+                OpCode::OpNotEqual => self.binary(&TokenKind::NotEqual)?,
+                OpCode::OpGreater => self.binary(&TokenKind::GreaterThan)?,
+                OpCode::OpGreaterEqual => self.binary(&TokenKind::GreaterEqual)?,
+                OpCode::OpLess => self.binary(&TokenKind::LessThan)?,
+                OpCode::OpLessEqual => self.binary(&TokenKind::LessEqual)?,
+                OpCode::OpAdd => self.binary(&TokenKind::Plus)?,
+                OpCode::OpSubtract => self.binary(&TokenKind::Minus)?,
+                OpCode::OpMultiply => self.binary(&TokenKind::Asterisk)?,
+                OpCode::OpDivide => self.binary(&TokenKind::ForwardSlash)?,
+                OpCode::OpModulo => self.binary(&TokenKind::Percentage)?,
+                OpCode::OpNot => self.unary(&TokenKind::Bang)?,
+                OpCode::OpNegate => self.unary(&TokenKind::Minus)?,
+                OpCode::OpInterpolate => {
+                    let count = self.read_byte(chunck);
+                    self.maybe_collect_garbage();
+                    let parts = self.stack.split_off(self.stack.len() - count);
+                    let string: String = parts.iter().map(|part| part.to_string()).collect();
+                    let string = Rc::from(string);
+                    self.heap.track_string(&string);
+                    self.stack.push(Value::String(string));
+                }
+                OpCode::OpPrint => {
+                    let value = self.pop();
+                    println!("{value}");
+                }
+                OpCode::OpJump => {
+                    let offset = self.read_u16(chunck);
+                    self.ip += offset;
+                }
+                OpCode::OpJumpIfFalse => {
+                    let offset = self.read_u16(chunck);
+                    if matches!(self.peek(), Value::None | Value::Boolean(false)) {
+                        self.ip += offset;
+                    }
+                }
+                OpCode::OpLoop => {
+                    let offset = self.read_u16(chunck);
+                    self.ip -= offset;
+                }
+                OpCode::OpClosure => {
+                    let index = self.read_u16(chunck);
+                    let Value::Prototype(ref prototype) = chunck.constant_pool[index] else {
+                        return Err(LoxError::RuntimeError(
+                            "Closure constant is not a function".to_string(),
+                        ));
+                    };
+
+                    let slot_base = self.frame().slot_base;
+                    let upvalues = prototype
+                        .upvalues
+                        .iter()
+                        .map(|source| match source.is_local {
+                            true => self.capture_upvalue(slot_base + source.index as usize),
+                            false => Rc::clone(&closure.upvalues[source.index as usize]),
+                        })
+                        .collect();
+
+                    self.maybe_collect_garbage();
+                    let closure = Rc::new(Closure {
+                        prototype: Rc::clone(prototype),
+                        upvalues,
+                    });
+                    self.heap.track_closure(&closure);
+                    self.stack.push(Value::Closure(closure));
+                }
+                OpCode::OpCall => {
+                    let argc = self.read_byte(chunck);
+                    self.call(argc)?;
+                    closure = Rc::clone(&self.frame().closure);
+                }
+                OpCode::OpReturn => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slot_base);
+                    self.stack.truncate(frame.slot_base);
+
+                    let Some(caller) = self.frames.last() else {
+                        return Ok(());
+                    };
+                    self.ip = caller.ip;
+                    closure = Rc::clone(&caller.closure);
+                    self.stack.push(result);
+                }
+            }
+        }
+    }
+
+    /// Calls the value sitting below the `argc` arguments on the stack
+    fn call(&mut self, argc: usize) -> Result<(), LoxError> {
+        let callee_slot = self.stack.len() - argc - 1;
+
+        match self.stack[callee_slot].clone() {
+            Value::Closure(closure) => {
+                if closure.prototype.arity != argc {
+                    return Err(LoxError::RuntimeError(format!(
+                        "Function \x1b[32m{}\x1b[0m expects {} arguments but got {}",
+                        closure.prototype.name, closure.prototype.arity, argc
+                    )));
+                }
+                if self.frames.len() == FRAMES_MAX {
+                    return Err(LoxError::RuntimeError(format!(
+                        "Stack overflow, more than {FRAMES_MAX} nested calls"
+                    )));
+                }
+
+                self.frames.last_mut().unwrap().ip = self.ip;
+                self.frames.push(CallFrame {
+                    closure,
+                    ip: 0,
+                    slot_base: callee_slot,
+                });
+                self.ip = 0;
+                Ok(())
+            }
+            other => Err(LoxError::RuntimeError(format!(
+                "Can only call functions, got \x1b[34m{:?}\x1b[0m",
+                other
+            ))),
+        }
+    }
+
+    /// Reuses the open upvalue for `slot` so closures share the variable
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .iter()
+            .position(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open >= slot));
+
+        if let Some(index) = position {
+            let existing = &self.open_upvalues[index];
+            if matches!(*existing.borrow(), Upvalue::Open(open) if open == slot) {
+                return Rc::clone(existing);
+            }
+        }
+
+        self.maybe_collect_garbage();
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.heap.track_upvalue(&upvalue);
+        let index = position.unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(index, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Moves every captured variable at or above `from` off the stack
+    fn close_upvalues(&mut self, from: usize) {
+        let keep = self
+            .open_upvalues
+            .iter()
+            .position(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(slot) if slot >= from))
+            .unwrap_or(self.open_upvalues.len());
+
+        for upvalue in self.open_upvalues.drain(keep..) {
+            let mut upvalue = upvalue.borrow_mut();
+            if let Upvalue::Open(slot) = *upvalue {
+                *upvalue = Upvalue::Closed(self.stack[slot].clone());
+            }
+        }
+    }
+
+    /// Called before every heap allocation. Whatever is about to be
+    /// allocated must already be reachable from a root.
+    fn maybe_collect_garbage(&mut self) {
+        if self.heap.should_collect() {
+            self.heap.collect(
+                self.stack.iter().chain(self.globals.iter()),
+                self.frames.iter().map(|frame| &frame.closure),
+                self.open_upvalues.iter(),
+            );
         }
     }
 
-    #[allow(unused)]
-    pub fn interpret(&mut self, chunck: Chunk) {}
+    fn frame(&self) -> &CallFrame {
+        self.frames
+            .last()
+            .expect("The script frame outlives execution")
+    }
+
+    fn trace_instruction(&self, chunck: &Chunk) {
+        let stack: String = self
+            .stack
+            .iter()
+            .map(|value| format!("[ {value} ]"))
+            .collect();
+        eprintln!("          {stack}");
+        eprintln!("{}", disassemble_instruction(chunck, self.ip).0);
+    }
+
+    fn read_byte(&mut self, chunck: &Chunk) -> usize {
+        let operand = chunck.code[self.ip];
+        self.ip += 1;
+        operand as usize
+    }
+
+    fn read_u16(&mut self, chunck: &Chunk) -> usize {
+        let operand = chunck.read_u16(self.ip);
+        self.ip += 2;
+        operand as usize
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Verified chunks never underflow")
+    }
+
+    fn peek(&self) -> &Value {
+        self.stack.last().expect("Verified chunks never underflow")
+    }
+
+    fn set_global(&mut self, index: usize, value: Value) {
+        if index >= self.globals.len() {
+            self.globals.resize(index + 1, Value::None);
+        }
+        self.globals[index] = value;
+    }
+
+    fn binary(&mut self, operator: &TokenKind) -> Result<(), LoxError> {
+        let rhs = self.pop();
+        let lhs = self.pop();
+        let value = binary_op(operator, lhs, rhs).map_err(LoxError::RuntimeError)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn unary(&mut self, operator: &TokenKind) -> Result<(), LoxError> {
+        let rhs = self.pop();
+        let value = unary_op(operator, rhs).map_err(LoxError::RuntimeError)?;
+        self.stack.push(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+
+    use super::{InterpretResult, VM};
+    use crate::{
+        bytecode::{Chunk, OpCode::*},
+        compiler::Compiler,
+        error::ErrorBag,
+        intern::Symbol,
+        lexer::Position,
+        optimizer::{fold_constants, peephole},
+        test_support::{compile, resolve},
+        value::{Prototype, UpvalueSource, Value},
+    };
+
+    fn run(program: &str) -> (InterpretResult, VM) {
+        let mut vm = VM::new();
+        (vm.interpret(compile(program)), vm)
+    }
+
+    #[test]
+    fn vm_promotes_integers_like_the_interpreter() {
+        let (result, vm) = run("let x = 1 + 2.5; let y = 7 % 3 * -2; let z = !(x >= y);");
+
+        assert_eq!(result, InterpretResult::InterpretOk);
+        assert!(matches!(vm.globals[0], Value::Decimal(d) if d == 3.5));
+        assert!(matches!(vm.globals[1], Value::Integer(-2)));
+        assert!(matches!(vm.globals[2], Value::Boolean(false)));
+    }
+
+    #[test]
+    fn vm_optimized_programs_match_unoptimized() {
+        let program = "let a = 60 * 60 * 24; let b = -(3) + a; b = b % 7; let c = b; \
+                       let d = !(c == 4) != (2.5 >= 1); let e = 1 / 2 * 3.0;";
+
+        let (result, vm) = run(program);
+        let mut error_bag = ErrorBag { errors: vec![] };
+        let mut ast = resolve(program);
+        fold_constants(&mut ast);
+        let chunk = peephole(Compiler::new(&mut error_bag).compile(&ast));
+        let mut optimized = VM::new();
+
+        assert_eq!(result, InterpretResult::InterpretOk);
+        assert_eq!(optimized.interpret(chunk), InterpretResult::InterpretOk);
+        assert_eq!(
+            format!("{:?}", vm.globals),
+            format!("{:?}", optimized.globals)
+        );
+    }
+
+    #[test]
+    fn vm_reports_integer_arithmetic_errors() {
+        let programs = [
+            "let a = 1; let b = a / 0;",
+            "let a = 1; let b = a % (a - 1);",
+            "let a = 9223372036854775807; let b = a + 1;",
+            "let a = -9223372036854775807 - 1; let b = -a;",
+            "let a = 9223372036854775807; let b = a * a;",
+        ];
+
+        for program in programs {
+            let (result, _) = run(program);
+            assert_eq!(result, InterpretResult::RuntimeError, "{program}");
+        }
+    }
+
+    #[test]
+    fn vm_interpolates_strings() {
+        let (result, vm) = run("let n = 2; let s = \"${n} + ${n / 4.0} is ${n + n / 4.0}\";");
+
+        assert_eq!(result, InterpretResult::InterpretOk);
+        assert!(matches!(vm.globals[1], Value::String(ref s) if &**s == "2 + 0.5 is 2.5"));
+    }
+
+    #[test]
+    fn vm_reports_runtime_errors() {
+        let (result, _) = run("let x = true + 1;");
+
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    fn assemble(code: &[u8], constant_pool: Vec<Value>) -> Chunk {
+        let span = Position::new(1, 1);
+        let mut chunk = Chunk::new();
+        chunk.constant_pool = constant_pool;
+        for &byte in code {
+            chunk.write(byte, &span);
+        }
+        chunk
+    }
+
+    fn function(name: &str, upvalues: Vec<UpvalueSource>, chunk: Chunk) -> Value {
+        Value::Prototype(Rc::new(Prototype {
+            name: Symbol::intern(name),
+            arity: 0,
+            upvalues,
+            chunk,
+        }))
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn vm_closures_keep_captured_variables_alive() {
+        // fn outer() { let x = 10; fn inner() { x = x + 1; return x; } return inner; }
+        let inner = assemble(
+            &[
+                OpGetUpvalue as u8, 0,
+                OpConstant as u8, 0, 0,
+                OpAdd as u8,
+                OpSetUpvalue as u8, 0,
+                OpReturn as u8,
+            ],
+            vec![Value::Integer(1)],
+        );
+        let outer = assemble(
+            &[
+                OpConstant as u8, 0, 0,
+                OpClosure as u8, 0, 1,
+                OpReturn as u8,
+            ],
+            vec![
+                Value::Integer(10),
+                function("inner", vec![UpvalueSource { is_local: true, index: 1 }], inner),
+            ],
+        );
+        // let counter = outer(); counter(); let result = counter();
+        let script = assemble(
+            &[
+                OpClosure as u8, 0, 0,
+                OpCall as u8, 0,
+                OpDefineGlobal as u8, 0, 0,
+                OpGetGlobal as u8, 0, 0,
+                OpCall as u8, 0,
+                OpPop as u8,
+                OpGetGlobal as u8, 0, 0,
+                OpCall as u8, 0,
+                OpDefineGlobal as u8, 0, 1,
+                OpNone as u8,
+                OpReturn as u8,
+            ],
+            vec![function("outer", vec![], outer)],
+        );
+
+        let mut vm = VM::new();
+        vm.set_gc_stress(true);
+
+        assert_eq!(vm.interpret(script), InterpretResult::InterpretOk);
+        assert!(matches!(vm.globals[1], Value::Integer(12)));
+        assert!(vm.gc_stats().collections > 0);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn vm_reports_stack_overflow() {
+        // fn f() { return f(); } f();
+        let recurse = assemble(
+            &[
+                OpGetGlobal as u8, 0, 0,
+                OpCall as u8, 0,
+                OpReturn as u8,
+            ],
+            vec![],
+        );
+        let script = assemble(
+            &[
+                OpClosure as u8, 0, 0,
+                OpDefineGlobal as u8, 0, 0,
+                OpGetGlobal as u8, 0, 0,
+                OpCall as u8, 0,
+                OpReturn as u8,
+            ],
+            vec![function("f", vec![], recurse)],
+        );
+
+        assert_eq!(VM::new().interpret(script), InterpretResult::RuntimeError);
+    }
 }